@@ -0,0 +1,160 @@
+//! Compares the full and the packed sprite instance layouts.
+//!
+//! Instances are uploaded the way the renderer does it every frame, with
+//! `Queue::write_buffer` into a vertex buffer, then submitting and waiting for the device
+//! so the copy to the buffer is included. Throughput is reported in sprites, so both layouts
+//! are compared on the same scale, and the bytes each layout takes per instance and per
+//! frame are printed before the runs.
+//!
+//! Without an adapter the groups are suffixed with "(cpu)" and only copy the instances into
+//! a byte buffer. That is a lower bound on the upload, leaving out the driver and the
+//! transfer to the GPU.
+
+use std::mem::size_of;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use wgpu_tilemap_renderer::{PackedSpriteInstance, SpriteInstance};
+
+const SPRITE_COUNTS: [usize; 2] = [100_000, 1_000_000];
+
+fn sprites(count: usize) -> Vec<SpriteInstance> {
+    (0..count)
+        .map(|i| {
+            SpriteInstance::new(
+                [16., 16.],
+                [(i % 8) as f32 * 16., 0.],
+                [(i % 1000) as f32 * 4.5, (i / 1000) as f32 * 3.],
+            )
+            .with_scale([3., 3.])
+            .with_layer(1.)
+            .with_flip(i % 2 == 0, false)
+        })
+        .collect()
+}
+
+fn request_device() -> Option<(wgpu::Device, wgpu::Queue)> {
+    let instance = wgpu::Instance::default();
+    let adapter =
+        pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions::default()))?;
+    pollster::block_on(adapter.request_device(
+        &wgpu::DeviceDescriptor {
+            label: Some("benchmark device"),
+            features: wgpu::Features::empty(),
+            limits: adapter.limits(),
+        },
+        None,
+    ))
+    .ok()
+}
+
+enum Upload<'a> {
+    Gpu {
+        device: &'a wgpu::Device,
+        queue: &'a wgpu::Queue,
+        buffer: wgpu::Buffer,
+    },
+    Cpu(Vec<u8>),
+}
+
+impl<'a> Upload<'a> {
+    fn new(gpu: Option<&'a (wgpu::Device, wgpu::Queue)>, size: usize) -> Self {
+        match gpu {
+            Some((device, queue)) => Self::Gpu {
+                device,
+                queue,
+                buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("benchmark instance buffer"),
+                    size: size as u64,
+                    usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+            },
+            None => Self::Cpu(Vec::with_capacity(size)),
+        }
+    }
+
+    fn write<T: bytemuck::Pod>(&mut self, instances: &[T]) {
+        match self {
+            Self::Gpu {
+                device,
+                queue,
+                buffer,
+            } => {
+                queue.write_buffer(buffer, 0, bytemuck::cast_slice(instances));
+                queue.submit([]);
+                device.poll(wgpu::Maintain::Wait);
+            }
+            Self::Cpu(staging) => {
+                staging.clear();
+                staging.extend_from_slice(bytemuck::cast_slice(instances));
+            }
+        }
+    }
+}
+
+fn group_name(name: &str, gpu: Option<&(wgpu::Device, wgpu::Queue)>) -> String {
+    match gpu {
+        Some(_) => name.to_string(),
+        None => format!("{name} (cpu)"),
+    }
+}
+
+fn print_sizes() {
+    for (layout, size) in [
+        ("full", size_of::<SpriteInstance>()),
+        ("packed", size_of::<PackedSpriteInstance>()),
+    ] {
+        for count in SPRITE_COUNTS {
+            println!(
+                "{layout}: {size} bytes/instance, {:.1} MiB/frame for {count} sprites",
+                (size * count) as f64 / (1024. * 1024.)
+            );
+        }
+    }
+}
+
+fn bench_upload(c: &mut Criterion) {
+    print_sizes();
+    let gpu = request_device();
+    let mut group = c.benchmark_group(group_name("upload", gpu.as_ref()));
+    for count in SPRITE_COUNTS {
+        let full = sprites(count);
+        let packed: Vec<PackedSpriteInstance> = full.iter().map(Into::into).collect();
+
+        group.throughput(Throughput::Elements(count as u64));
+        let mut upload = Upload::new(gpu.as_ref(), count * size_of::<SpriteInstance>());
+        group.bench_with_input(BenchmarkId::new("full", count), &full, |b, full| {
+            b.iter(|| upload.write(black_box(full)))
+        });
+
+        let mut upload = Upload::new(gpu.as_ref(), count * size_of::<PackedSpriteInstance>());
+        group.bench_with_input(BenchmarkId::new("packed", count), &packed, |b, packed| {
+            b.iter(|| upload.write(black_box(packed)))
+        });
+    }
+    group.finish();
+}
+
+// The cost of packing every frame, for sprites kept in the full layout on the CPU
+fn bench_pack_and_upload(c: &mut Criterion) {
+    let gpu = request_device();
+    let mut group = c.benchmark_group(group_name("pack and upload", gpu.as_ref()));
+    for count in SPRITE_COUNTS {
+        let full = sprites(count);
+        let mut packed = Vec::with_capacity(count);
+        let mut upload = Upload::new(gpu.as_ref(), count * size_of::<PackedSpriteInstance>());
+
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::new("packed", count), &full, |b, full| {
+            b.iter(|| {
+                packed.clear();
+                packed.extend(black_box(full).iter().map(PackedSpriteInstance::from));
+                upload.write(&packed);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_upload, bench_pack_and_upload);
+criterion_main!(benches);
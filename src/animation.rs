@@ -1,64 +1,520 @@
 use instant::Duration;
-use wgpu::util::DeviceExt;
 
-pub struct Animation<const N: usize> {
-    time_since_last_frame: Duration,
+use crate::{constants::Position, renderer::SpriteInstance};
+
+/// What a clip does after its last frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Playback {
+    /// Start over from the first frame
+    Loop,
+    /// Play backwards to the first frame, then forwards again
+    PingPong,
+    /// Stop on the last frame
+    Once,
+}
+
+/// Atlas frames of the same size, each shown for `frame_duration`.
+#[derive(Debug, Clone)]
+pub struct Clip {
+    // Texture origins in the atlas
+    frames: Vec<[f32; 2]>,
     frame_duration: Duration,
-    current_animation_index: usize,
-    current_frame: usize,
-    frames: [Vec<u32>; N],
+    playback: Playback,
 }
 
-impl<const N: usize> Animation<N> {
-    pub fn new(device: &wgpu::Device, frames: [Vec<u32>; N], frame_duration: Duration) -> Self {
-        let uniform = AnimationUniform::new(0);
-        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Animation Buffer"),
-            contents: bytemuck::cast_slice(&[uniform]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
+impl Clip {
+    pub fn new(frames: &[Position], frame_duration: Duration, playback: Playback) -> Self {
+        assert!(!frames.is_empty(), "a clip needs at least one frame");
+        Self {
+            frames: frames.iter().map(|frame| [frame.x, frame.y]).collect(),
+            frame_duration,
+            playback,
+        }
+    }
+}
 
-        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-            label: Some("animation bind group layout"),
-            entries: &[wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStages::FRAGMENT,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            }],
-        });
+/// Plays a [`Clip`] on a sprite, advanced by the time since the last frame.
+#[derive(Debug, Clone)]
+pub struct Animation {
+    clip: Clip,
+    frame: usize,
+    // Time spent on the current frame
+    elapsed: Duration,
+    // Playing towards the first frame, for ping-pong clips
+    reverse: bool,
+    finished: bool,
+}
 
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("animation Bind Group"),
-            layout: &bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: buffer.as_entire_binding(),
-            }],
-        });
+impl Animation {
+    pub fn new(clip: Clip) -> Self {
+        Self {
+            clip,
+            frame: 0,
+            elapsed: Duration::ZERO,
+            reverse: false,
+            finished: false,
+        }
+    }
 
+    /// Switch to another clip, starting from its first frame.
+    pub fn play(&mut self, clip: Clip) {
+        *self = Self::new(clip);
+    }
+
+    /// Switch to another clip at the same frame and time into it, so a walk cycle keeps its
+    /// step when the direction changes.
+    pub fn play_in_phase(&mut self, clip: Clip) {
+        self.frame %= clip.frames.len();
+        self.clip = clip;
+        self.finished = false;
+    }
+
+    /// Whether a [`Playback::Once`] clip has reached its last frame.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Advance the animation, returns whether the frame changed.
+    pub fn update(&mut self, delta: Duration) -> bool {
+        self.update_with(delta, |_| {})
+    }
+
+    /// Advance the animation, calling `on_frame` with every frame that is entered on the way.
+    pub fn update_with(&mut self, delta: Duration, mut on_frame: impl FnMut(usize)) -> bool {
+        if self.finished || self.clip.frame_duration.is_zero() {
+            return false;
+        }
+        let frame = self.frame;
+        self.elapsed += delta;
+        // A long frame can skip over several short ones
+        while self.elapsed >= self.clip.frame_duration && !self.finished {
+            self.elapsed -= self.clip.frame_duration;
+            let previous = self.frame;
+            self.advance();
+            if self.frame != previous {
+                on_frame(self.frame);
+            }
+        }
+        self.frame != frame
+    }
+
+    fn advance(&mut self) {
+        let last = self.clip.frames.len() - 1;
+        match self.clip.playback {
+            Playback::Loop => {
+                self.frame = if self.frame == last {
+                    0
+                } else {
+                    self.frame + 1
+                }
+            }
+            Playback::Once if self.frame == last => self.finished = true,
+            Playback::Once => self.frame += 1,
+            Playback::PingPong if last == 0 => {}
+            Playback::PingPong => {
+                if self.frame == last {
+                    self.reverse = true;
+                } else if self.frame == 0 {
+                    self.reverse = false;
+                }
+                if self.reverse {
+                    self.frame -= 1;
+                } else {
+                    self.frame += 1;
+                }
+            }
+        }
+    }
+
+    /// Show the current frame on `sprite`.
+    pub fn apply(&self, sprite: &mut SpriteInstance) {
+        sprite.set_texture_origin(self.clip.frames[self.frame]);
+    }
+}
+
+/// Direction a character is looking in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Facing {
+    Up,
+    #[default]
+    Down,
+    Left,
+    Right,
+}
+
+/// Values the transitions of an [`AnimationStateMachine`] are tested against.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct AnimationParams {
+    pub velocity: [f32; 2],
+    pub facing: Facing,
+}
+
+impl AnimationParams {
+    /// Set the velocity, turning to face the direction of movement. Standing still keeps
+    /// the last facing.
+    pub fn set_velocity(&mut self, velocity: [f32; 2]) {
+        self.velocity = velocity;
+        let [x, y] = velocity;
+        if x == 0. && y == 0. {
+            return;
+        }
+        self.facing = if x.abs() > y.abs() {
+            if x < 0. {
+                Facing::Left
+            } else {
+                Facing::Right
+            }
+        } else if y < 0. {
+            Facing::Down
+        } else {
+            Facing::Up
+        };
+    }
+
+    pub fn is_moving(&self) -> bool {
+        self.velocity != [0., 0.]
+    }
+}
+
+/// A named clip in an [`AnimationStateMachine`].
+#[derive(Debug, Clone)]
+pub struct AnimationState {
+    name: &'static str,
+    clip: Clip,
+    flip_x: bool,
+    // Fired when the frame is entered
+    events: Vec<(usize, &'static str)>,
+}
+
+impl AnimationState {
+    pub fn new(name: &'static str, clip: Clip) -> Self {
         Self {
-            time_since_last_frame: Duration::from_millis(0),
-            frame_duration,
-            current_frame: 0,
-            current_animation_index: 0,
-            frames,
+            name,
+            clip,
+            flip_x: false,
+            events: Vec::new(),
         }
     }
+
+    /// Mirror the clip, so one set of frames can serve both left and right.
+    pub fn with_flip_x(mut self, flip_x: bool) -> Self {
+        self.flip_x = flip_x;
+        self
+    }
+
+    /// Fire `event` whenever `frame` is shown, for footsteps, hit frames and the like.
+    pub fn with_event(mut self, frame: usize, event: &'static str) -> Self {
+        self.events.push((frame, event));
+        self
+    }
+
+    fn events_on(&self, frame: usize) -> impl Iterator<Item = &'static str> + '_ {
+        self.events
+            .iter()
+            .filter(move |(event_frame, _)| *event_frame == frame)
+            .map(|(_, event)| *event)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Transition {
+    // None to leave from any state
+    from: Option<&'static str>,
+    to: &'static str,
+    condition: fn(&AnimationParams) -> bool,
+    keep_phase: bool,
 }
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct AnimationUniform {
-    tile_index: u32,
+/// Switches between named [`AnimationState`]s as its parameters change.
+///
+/// Transitions are tested in the order they were added, and the first one whose condition
+/// holds is taken. Switching is instant, there is no blending between clips.
+#[derive(Debug, Clone)]
+pub struct AnimationStateMachine {
+    states: Vec<AnimationState>,
+    transitions: Vec<Transition>,
+    current: usize,
+    animation: Animation,
+    pub params: AnimationParams,
+    // Fired during the last update
+    events: Vec<&'static str>,
+}
+
+impl AnimationStateMachine {
+    pub fn new(initial: AnimationState) -> Self {
+        Self {
+            animation: Animation::new(initial.clip.clone()),
+            events: initial.events_on(0).collect(),
+            states: vec![initial],
+            transitions: Vec::new(),
+            current: 0,
+            params: AnimationParams::default(),
+        }
+    }
+
+    pub fn with_state(mut self, state: AnimationState) -> Self {
+        self.states.push(state);
+        self
+    }
+
+    /// Go from `from`, or any state when it's None, to `to` when `condition` holds. The new
+    /// clip starts from its first frame.
+    pub fn with_transition(
+        self,
+        from: Option<&'static str>,
+        to: &'static str,
+        condition: fn(&AnimationParams) -> bool,
+    ) -> Self {
+        self.add_transition(from, to, condition, false)
+    }
+
+    /// Like [`Self::with_transition`], but the new clip continues at the frame the old one
+    /// was on.
+    pub fn with_phase_transition(
+        self,
+        from: Option<&'static str>,
+        to: &'static str,
+        condition: fn(&AnimationParams) -> bool,
+    ) -> Self {
+        self.add_transition(from, to, condition, true)
+    }
+
+    fn add_transition(
+        mut self,
+        from: Option<&'static str>,
+        to: &'static str,
+        condition: fn(&AnimationParams) -> bool,
+        keep_phase: bool,
+    ) -> Self {
+        self.transitions.push(Transition {
+            from,
+            to,
+            condition,
+            keep_phase,
+        });
+        self
+    }
+
+    pub fn state(&self) -> &'static str {
+        self.states[self.current].name
+    }
+
+    /// Events fired by the frames shown during the last update.
+    pub fn events(&self) -> &[&'static str] {
+        &self.events
+    }
+
+    /// Take a transition if one applies, then advance the current clip. Returns whether the
+    /// sprite needs to be updated.
+    pub fn update(&mut self, delta: Duration) -> bool {
+        self.events.clear();
+        let current = self.state();
+        let transition = self.transitions.iter().find(|transition| {
+            transition.from.is_none_or(|from| from == current)
+                && transition.to != current
+                && (transition.condition)(&self.params)
+        });
+        let switched = match transition {
+            Some(transition) => {
+                let keep_phase = transition.keep_phase;
+                let to = transition.to;
+                self.switch(to, keep_phase);
+                true
+            }
+            None => false,
+        };
+
+        let state = &self.states[self.current];
+        let events = &mut self.events;
+        let changed = self
+            .animation
+            .update_with(delta, |frame| events.extend(state.events_on(frame)));
+        switched || changed
+    }
+
+    fn switch(&mut self, to: &'static str, keep_phase: bool) {
+        let Some(index) = self.states.iter().position(|state| state.name == to) else {
+            log::warn!("animation state {to} does not exist");
+            return;
+        };
+        self.current = index;
+        let state = &self.states[index];
+        if keep_phase {
+            self.animation.play_in_phase(state.clip.clone());
+        } else {
+            self.animation.play(state.clip.clone());
+            self.events.extend(state.events_on(0));
+        }
+    }
+
+    /// Show the current frame on `sprite`.
+    pub fn apply(&self, sprite: &mut SpriteInstance) {
+        self.animation.apply(sprite);
+        sprite.set_flip_x(self.states[self.current].flip_x);
+    }
 }
 
-impl AnimationUniform {
-    pub fn new(tile_index: u32) -> Self {
-        Self { tile_index }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(100);
+
+    fn clip(frames: usize, playback: Playback) -> Clip {
+        let frames: Vec<_> = (0..frames)
+            .map(|i| Position {
+                x: i as f32 * 16.,
+                y: 0.,
+            })
+            .collect();
+        Clip::new(&frames, FRAME, playback)
+    }
+
+    // Frame shown after each of `steps` frame durations
+    fn frames(animation: &mut Animation, steps: usize) -> Vec<usize> {
+        (0..steps)
+            .map(|_| {
+                animation.update(FRAME);
+                animation.frame
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loop_wraps_to_first_frame() {
+        let mut animation = Animation::new(clip(3, Playback::Loop));
+        assert_eq!(frames(&mut animation, 5), [1, 2, 0, 1, 2]);
+        assert!(!animation.is_finished());
+    }
+
+    #[test]
+    fn ping_pong_reverses_without_repeating_ends() {
+        let mut animation = Animation::new(clip(3, Playback::PingPong));
+        assert_eq!(frames(&mut animation, 6), [1, 2, 1, 0, 1, 2]);
+        assert!(!animation.is_finished());
+    }
+
+    #[test]
+    fn ping_pong_single_frame_stays_put() {
+        let mut animation = Animation::new(clip(1, Playback::PingPong));
+        assert_eq!(frames(&mut animation, 3), [0, 0, 0]);
+    }
+
+    #[test]
+    fn once_stops_on_last_frame() {
+        let mut animation = Animation::new(clip(3, Playback::Once));
+        assert_eq!(frames(&mut animation, 2), [1, 2]);
+        assert!(!animation.is_finished());
+
+        // The last frame is shown for its full duration before the clip is done
+        assert!(!animation.update(FRAME));
+        assert!(animation.is_finished());
+        assert_eq!(frames(&mut animation, 2), [2, 2]);
+    }
+
+    #[test]
+    fn long_update_enters_every_frame_on_the_way() {
+        let mut animation = Animation::new(clip(4, Playback::Loop));
+        let mut entered = Vec::new();
+        animation.update_with(FRAME * 5 + FRAME / 2, |frame| entered.push(frame));
+        assert_eq!(entered, [1, 2, 3, 0, 1]);
+        assert_eq!(animation.frame, 1);
+        assert_eq!(animation.elapsed, FRAME / 2);
+    }
+
+    fn walker() -> AnimationStateMachine {
+        AnimationStateMachine::new(AnimationState::new("idle", clip(1, Playback::Loop)))
+            .with_state(
+                AnimationState::new("walk_down", clip(4, Playback::Loop))
+                    .with_event(0, "start")
+                    .with_event(2, "step"),
+            )
+            .with_state(AnimationState::new("walk_up", clip(4, Playback::Loop)))
+            .with_transition(None, "idle", |params| !params.is_moving())
+            .with_transition(Some("idle"), "walk_down", |params| {
+                params.is_moving() && params.facing == Facing::Down
+            })
+            .with_transition(Some("idle"), "walk_up", |params| {
+                params.is_moving() && params.facing == Facing::Up
+            })
+            .with_phase_transition(Some("walk_down"), "walk_up", |params| {
+                params.facing == Facing::Up
+            })
+            .with_phase_transition(Some("walk_up"), "walk_down", |params| {
+                params.facing == Facing::Down
+            })
+    }
+
+    #[test]
+    fn transitions_follow_params() {
+        let mut machine = walker();
+        assert_eq!(machine.state(), "idle");
+
+        // Nothing applies while standing still
+        assert!(!machine.update(FRAME));
+        assert_eq!(machine.state(), "idle");
+
+        machine.params.set_velocity([0., -1.]);
+        assert!(machine.update(Duration::ZERO));
+        assert_eq!(machine.state(), "walk_down");
+
+        machine.params.set_velocity([0., 0.]);
+        assert!(machine.update(Duration::ZERO));
+        assert_eq!(machine.state(), "idle");
+        // Standing still keeps facing the way the character last moved
+        assert_eq!(machine.params.facing, Facing::Down);
+    }
+
+    #[test]
+    fn phase_transition_keeps_frame_and_time() {
+        let mut machine = walker();
+        machine.params.set_velocity([0., -1.]);
+        machine.update(Duration::ZERO);
+        machine.update(FRAME * 2 + FRAME / 4);
+        assert_eq!(machine.animation.frame, 2);
+
+        machine.params.set_velocity([0., 1.]);
+        assert!(machine.update(Duration::ZERO));
+        assert_eq!(machine.state(), "walk_up");
+        assert_eq!(machine.animation.frame, 2);
+        assert_eq!(machine.animation.elapsed, FRAME / 4);
+    }
+
+    #[test]
+    fn plain_transition_restarts_clip() {
+        let mut machine = walker();
+        machine.params.set_velocity([0., -1.]);
+        machine.update(Duration::ZERO);
+        machine.update(FRAME * 2);
+
+        machine.params.set_velocity([0., 0.]);
+        machine.update(Duration::ZERO);
+        machine.params.set_velocity([0., 1.]);
+        machine.update(Duration::ZERO);
+        assert_eq!(machine.state(), "walk_up");
+        assert_eq!(machine.animation.frame, 0);
+        assert_eq!(machine.animation.elapsed, Duration::ZERO);
+    }
+
+    #[test]
+    fn events_fire_on_their_frames() {
+        let mut machine = walker();
+        assert!(machine.events().is_empty());
+
+        // Entering a state from its first frame fires that frame's events
+        machine.params.set_velocity([0., -1.]);
+        machine.update(Duration::ZERO);
+        assert_eq!(machine.events(), ["start"]);
+
+        machine.update(FRAME);
+        assert!(machine.events().is_empty());
+        machine.update(FRAME);
+        assert_eq!(machine.events(), ["step"]);
+
+        // Skipping over frames still fires them, and events only last one update
+        machine.update(FRAME * 2);
+        assert_eq!(machine.events(), ["start"]);
+        machine.update(Duration::ZERO);
+        assert!(machine.events().is_empty());
     }
 }
@@ -2,6 +2,8 @@ use std::collections::HashMap;
 
 use rand::Rng;
 
+use crate::renderer::{Light, LightShape, PALETTE_SIZE};
+
 pub struct Tiles {
     pub floor1: Position,
     pub floor2: Position,
@@ -143,6 +145,26 @@ pub const TILES: Tiles = Tiles {
     },
 };
 
+// Colour schemes for indexed sprites. The row is picked per instance, the column is the
+// palette index stored in the sprite's red channel.
+pub const PALETTES: [[u32; PALETTE_SIZE]; 3] = [
+    // default, greyscale ramp
+    [
+        0x000000, 0x111111, 0x222222, 0x333333, 0x444444, 0x555555, 0x666666, 0x777777, 0x888888,
+        0x999999, 0xAAAAAA, 0xBBBBBB, 0xCCCCCC, 0xDDDDDD, 0xEEEEEE, 0xFFFFFF,
+    ],
+    // red team
+    [
+        0x000000, 0x1A0606, 0x330B0B, 0x4D1111, 0x661616, 0x801C1C, 0x992222, 0xB32727, 0xCC2D2D,
+        0xE63333, 0xFF3838, 0xFF5252, 0xFF6B6B, 0xFF8585, 0xFF9E9E, 0xFFB8B8,
+    ],
+    // blue team
+    [
+        0x000000, 0x06061A, 0x0B0B33, 0x11114D, 0x161666, 0x1C1C80, 0x222299, 0x2727B3, 0x2D2DCC,
+        0x3333E6, 0x3838FF, 0x5252FF, 0x6B6BFF, 0x8585FF, 0x9E9EFF, 0xB8B8FF,
+    ],
+];
+
 pub const TILE_SIZE: usize = 48;
 pub const SPRITE_SIZE: f32 = 16.;
 
@@ -159,8 +181,8 @@ pub const MAP: &str = r##"
 #...############........#####..#####...#
 #...############........#####..#####...#
 #...##........##........##........##...#
-#...##........##.......................#
-#...##........##.......................#
+#...##........##.................~~~...#
+#...##........##................~~~~...#
 #...##........##........##........##...#
 #...##........##........##........##...#
 #...#####..#####........#####..#####...#
@@ -190,7 +212,56 @@ fn _get_floor_tile() -> Position {
     FLOOR_TILES[tile_num]
 }
 
-type ParsedMap = (HashMap<(usize, usize), Position>, Vec<f32>, u32, u32);
+// Sprites on a higher layer are always drawn on top, within a layer they are y-sorted
+pub const GROUND_LAYER: f32 = 0.;
+// Flat things lying on the ground, drawn over it but under everything else
+pub const DECAL_LAYER: f32 = 0.5;
+pub const OBJECT_LAYER: f32 = 1.;
+
+// The 3x3 block of wall tiles starting at wall_top_edge_left, as a nine-slice frame with
+// borders one sprite wide
+pub const FRAME_ORIGIN: Position = TILES.wall_top_edge_left;
+pub const FRAME_SIZE: f32 = 3. * SPRITE_SIZE;
+
+// A dot shrinking over four frames, for sparks and other small particles
+pub const SPARK_ORIGIN: Position = Position { x: 48., y: 64. };
+pub const SPARK_SIZE: f32 = 8.;
+pub const SPARK_FRAMES: u32 = 4;
+
+// A gem drawn in palette indices, coloured by one of the PALETTES rows
+pub const GEM_ORIGIN: Position = Position { x: 144., y: 48. };
+
+/// Frames a map tile cycles through, every tile of the kind in step.
+pub struct AnimatedTile {
+    pub frames: &'static [Position],
+    // Seconds
+    pub frame_duration: f32,
+}
+
+// Ripples scrolling to the right over four frames
+pub const WATER: AnimatedTile = AnimatedTile {
+    frames: &[
+        Position { x: 80., y: 64. },
+        Position { x: 96., y: 64. },
+        Position { x: 112., y: 64. },
+        Position { x: 128., y: 64. },
+    ],
+    frame_duration: 0.25,
+};
+
+// Registered with the renderer in this order, MapTile::animation indexes into it
+pub const ANIMATED_TILES: [AnimatedTile; 1] = [WATER];
+const WATER_ANIMATION: u32 = 0;
+
+#[derive(Clone, Copy)]
+pub struct MapTile {
+    pub texture_origin: Position,
+    pub layer: f32,
+    // Index into ANIMATED_TILES, texture_origin is the first frame
+    pub animation: Option<u32>,
+}
+
+type ParsedMap = (HashMap<(usize, usize), MapTile>, Vec<f32>, u32, u32);
 
 pub fn parse_map(map: &str) -> ParsedMap {
     let mut tiles = HashMap::new();
@@ -208,10 +279,32 @@ pub fn parse_map(map: &str) -> ParsedMap {
             let (tile, color) = match char {
                 '#' => {
                     let (wall_type, _occlude) = determine_wall_type(x, y, &map_lines);
-                    (wall_type, 0.0)
+                    (
+                        MapTile {
+                            texture_origin: wall_type,
+                            layer: OBJECT_LAYER,
+                            animation: None,
+                        },
+                        0.0,
+                    )
                     // (wall_type, if occlude { 0.0 } else { f32::MAX })
                 }
-                _ => (_get_floor_tile(), f32::MAX),
+                '~' => (
+                    MapTile {
+                        texture_origin: WATER.frames[0],
+                        layer: GROUND_LAYER,
+                        animation: Some(WATER_ANIMATION),
+                    },
+                    f32::MAX,
+                ),
+                _ => (
+                    MapTile {
+                        texture_origin: _get_floor_tile(),
+                        layer: GROUND_LAYER,
+                        animation: None,
+                    },
+                    f32::MAX,
+                ),
             };
             tiles.insert((x, y), tile);
 
@@ -519,3 +612,113 @@ pub enum Types {
     PLAYER,
     ENVIRONMENT,
 }
+
+/// A light on the map, swaying and pulsing `frequency` times per 2π seconds.
+pub struct MapLight {
+    pub light: Light,
+    pub frequency: f32,
+}
+
+// Lights placed around the map when it is initialized
+pub const LIGHTS: [MapLight; 10] = [
+    MapLight {
+        light: Light {
+            position: [1200., 920.],
+            intensity: 3.,
+            falloff: 0.4,
+            color: [1., 1., 1.],
+            shape: LightShape::Point,
+        },
+        frequency: 2.,
+    },
+    MapLight {
+        light: Light {
+            position: [300., 900.],
+            intensity: 3.,
+            falloff: 0.2,
+            color: [0.7, 0.3, 0.1],
+            shape: LightShape::Point,
+        },
+        frequency: 2.5,
+    },
+    MapLight {
+        light: Light {
+            position: [150., 500.],
+            intensity: 3.,
+            falloff: 0.2,
+            color: [0.4, 0.2, 0.8],
+            shape: LightShape::Point,
+        },
+        frequency: 4.,
+    },
+    MapLight {
+        light: Light {
+            position: [300., 200.],
+            intensity: 3.,
+            falloff: 0.4,
+            color: [0.3, 0.2, 0.8],
+            shape: LightShape::Point,
+        },
+        frequency: 1.,
+    },
+    MapLight {
+        light: Light {
+            position: [500., 550.],
+            intensity: 6.,
+            falloff: 0.2,
+            color: [0.98, 0.34, 0.13],
+            shape: LightShape::Point,
+        },
+        frequency: 2.,
+    },
+    MapLight {
+        light: Light {
+            position: [1000., 550.],
+            intensity: 0.,
+            falloff: 0.2,
+            color: [1., 0.5, 0.3],
+            shape: LightShape::Point,
+        },
+        frequency: 1.5,
+    },
+    MapLight {
+        light: Light {
+            position: [1400., 550.],
+            intensity: 8.,
+            falloff: 0.2,
+            color: [0., 0.5, 0.3],
+            shape: LightShape::Point,
+        },
+        frequency: 3.,
+    },
+    MapLight {
+        light: Light {
+            position: [1800., 350.],
+            intensity: 2.,
+            falloff: 0.4,
+            color: [0.4, 0.8, 0.1],
+            shape: LightShape::Point,
+        },
+        frequency: 7.,
+    },
+    MapLight {
+        light: Light {
+            position: [1500., 150.],
+            intensity: 3.,
+            falloff: 0.4,
+            color: [0.7, 0.3, 0.1],
+            shape: LightShape::Point,
+        },
+        frequency: 1.,
+    },
+    MapLight {
+        light: Light {
+            position: [1800., 950.],
+            intensity: 3.,
+            falloff: 0.4,
+            color: [0.1, 1.0, 0.5],
+            shape: LightShape::Point,
+        },
+        frequency: 5.,
+    },
+];
@@ -1,12 +1,23 @@
-use crate::constants::Types;
+use crate::{
+    animation::AnimationStateMachine, constants::Types, renderer::SpriteInstance, store::Handle,
+};
+
+pub type SpriteHandle = Handle<SpriteInstance>;
 
 pub struct Entity {
     pub id: usize,
     pub kind: Types,
+    pub sprite: SpriteHandle,
+    pub animation: Option<AnimationStateMachine>,
 }
 
 impl Entity {
-    pub fn new(id: usize, kind: Types) -> Self {
-        Self { id, kind }
+    pub fn new(id: usize, kind: Types, sprite: SpriteHandle) -> Self {
+        Self {
+            id,
+            kind,
+            sprite,
+            animation: None,
+        }
     }
 }
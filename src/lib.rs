@@ -2,10 +2,15 @@ mod animation;
 mod constants;
 mod entity;
 mod renderer;
+mod store;
+mod tween;
 mod utils;
 mod world;
 mod world_state;
 
+// Exposed for the benchmarks
+pub use renderer::{PackedSpriteInstance, SpriteInstance};
+
 use winit::{
     dpi::PhysicalSize,
     event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
@@ -0,0 +1,321 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Context};
+
+use super::{resources::load_binary, SpriteInstance};
+
+/// Where a character lives in the atlas and how it sits on the line. All in atlas pixels.
+#[derive(Clone, Copy, Debug)]
+struct Glyph {
+    origin: [f32; 2],
+    size: [f32; 2],
+    // From the pen position to the top left of the glyph, y pointing down
+    offset: [f32; 2],
+    advance: f32,
+}
+
+/// A font whose glyphs are regions of the sprite atlas.
+///
+/// Fonts come either from a BMFont / AngelCode `.fnt` file in the text format, whose page is
+/// the sprite atlas, or from a grid of equally sized cells for simple pixel fonts.
+pub struct BitmapFont {
+    line_height: f32,
+    glyphs: HashMap<char, Glyph>,
+    kerning: HashMap<(char, char), f32>,
+}
+
+impl BitmapFont {
+    // Drawn for characters the font doesn't have
+    const REPLACEMENT: char = '?';
+
+    pub async fn load(file_name: &str) -> anyhow::Result<Self> {
+        let data = load_binary(file_name).await?;
+        let source = String::from_utf8(data)?;
+        Self::from_fnt(&source).with_context(|| format!("failed to parse {file_name}"))
+    }
+
+    /// Parse a font in the text `.fnt` format. Only single page fonts are supported, and the
+    /// page is assumed to be the sprite atlas.
+    pub fn from_fnt(source: &str) -> anyhow::Result<Self> {
+        let mut line_height = None;
+        let mut glyphs = HashMap::new();
+        let mut kerning = HashMap::new();
+
+        for line in source.lines() {
+            let mut words = line.split_whitespace();
+            let Some(tag) = words.next() else {
+                continue;
+            };
+            let attributes: HashMap<&str, &str> =
+                words.filter_map(|word| word.split_once('=')).collect();
+            let number = |key: &str| -> anyhow::Result<f32> {
+                attributes
+                    .get(key)
+                    .ok_or_else(|| anyhow!("{tag} is missing {key}"))?
+                    .parse::<f32>()
+                    .with_context(|| format!("invalid {key} in {tag}"))
+            };
+            let character = |key: &str| -> anyhow::Result<char> {
+                char::from_u32(number(key)? as u32).ok_or_else(|| anyhow!("invalid {key} in {tag}"))
+            };
+
+            match tag {
+                "common" => {
+                    if number("pages")? != 1. {
+                        anyhow::bail!("only fonts with a single page are supported");
+                    }
+                    line_height = Some(number("lineHeight")?);
+                }
+                "char" => {
+                    glyphs.insert(
+                        character("id")?,
+                        Glyph {
+                            origin: [number("x")?, number("y")?],
+                            size: [number("width")?, number("height")?],
+                            offset: [number("xoffset")?, number("yoffset")?],
+                            advance: number("xadvance")?,
+                        },
+                    );
+                }
+                "kerning" => {
+                    kerning.insert(
+                        (character("first")?, character("second")?),
+                        number("amount")?,
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            line_height: line_height.ok_or_else(|| anyhow!("missing common line"))?,
+            glyphs,
+            kerning,
+        })
+    }
+
+    /// A monospaced font laid out in a grid of `cell_size` cells starting at `origin`,
+    /// `columns` cells wide. `characters` lists the characters in the order of the cells.
+    pub fn grid(origin: [f32; 2], cell_size: [f32; 2], columns: usize, characters: &str) -> Self {
+        let glyphs = characters
+            .chars()
+            .enumerate()
+            .map(|(i, c)| {
+                let glyph = Glyph {
+                    origin: [
+                        origin[0] + (i % columns) as f32 * cell_size[0],
+                        origin[1] + (i / columns) as f32 * cell_size[1],
+                    ],
+                    size: cell_size,
+                    offset: [0., 0.],
+                    advance: cell_size[0],
+                };
+                (c, glyph)
+            })
+            .collect();
+
+        Self {
+            line_height: cell_size[1],
+            glyphs,
+            kerning: HashMap::new(),
+        }
+    }
+
+    /// Distance between two lines, in atlas pixels.
+    pub fn line_height(&self) -> f32 {
+        self.line_height
+    }
+
+    fn glyph(&self, c: char) -> Option<&Glyph> {
+        self.glyphs
+            .get(&c)
+            .or_else(|| self.glyphs.get(&Self::REPLACEMENT))
+    }
+
+    fn kerning(&self, previous: Option<char>, c: char) -> f32 {
+        previous
+            .and_then(|previous| self.kerning.get(&(previous, c)))
+            .copied()
+            .unwrap_or(0.)
+    }
+
+    // Width of a single line in atlas pixels
+    fn measure(&self, line: &str) -> f32 {
+        let mut width = 0.;
+        let mut previous = None;
+        for c in line.chars() {
+            width += self.kerning(previous, c) + self.glyph(c).map_or(0., |g| g.advance);
+            previous = Some(c);
+        }
+        width
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+/// A block of text laid out with a [`BitmapFont`] into sprite instances.
+///
+/// Lines break on `\n`, and on spaces once a line gets wider than the max width. Works in
+/// world space as well as for the overlay, see
+/// [`SpriteBuffer::Overlay`](super::SpriteBuffer::Overlay).
+pub struct Text<'a> {
+    font: &'a BitmapFont,
+    text: &'a str,
+    // Size of an atlas pixel in world units
+    scale: f32,
+    color: [f32; 4],
+    align: Align,
+    max_width: Option<f32>,
+    layer: f32,
+    lit: bool,
+}
+
+impl<'a> Text<'a> {
+    pub fn new(font: &'a BitmapFont, text: &'a str) -> Self {
+        Self {
+            font,
+            text,
+            scale: 1.,
+            color: [1., 1., 1., 1.],
+            align: Align::Left,
+            max_width: None,
+            layer: 0.,
+            lit: true,
+        }
+    }
+
+    pub fn with_scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn with_color(mut self, color: [f32; 4]) -> Self {
+        self.color = color;
+        self
+    }
+
+    pub fn with_align(mut self, align: Align) -> Self {
+        self.align = align;
+        self
+    }
+
+    /// Wrap lines wider than `max_width` world units. Alignment is relative to this width.
+    pub fn with_max_width(mut self, max_width: f32) -> Self {
+        self.max_width = Some(max_width);
+        self
+    }
+
+    pub fn with_layer(mut self, layer: f32) -> Self {
+        self.layer = layer;
+        self
+    }
+
+    /// Whether the scene lights affect the text, see [`SpriteInstance::with_lighting`].
+    pub fn with_lighting(mut self, lit: bool) -> Self {
+        self.lit = lit;
+        self
+    }
+
+    /// Width and height of the laid out text in world units.
+    pub fn size(&self) -> [f32; 2] {
+        let lines = self.lines();
+        [
+            self.block_width(&lines),
+            lines.len() as f32 * self.font.line_height * self.scale,
+        ]
+    }
+
+    /// The instances for every visible glyph, with the top left corner of the text at
+    /// `position`.
+    pub fn instances(&self, position: [f32; 2]) -> Vec<SpriteInstance> {
+        let lines = self.lines();
+        let block_width = self.block_width(&lines);
+        let mut instances = Vec::new();
+
+        for (i, line) in lines.iter().enumerate() {
+            let width = self.font.measure(line) * self.scale;
+            let mut pen = position[0]
+                + match self.align {
+                    Align::Left => 0.,
+                    Align::Center => (block_width - width) / 2.,
+                    Align::Right => block_width - width,
+                };
+            let top = position[1] - i as f32 * self.font.line_height * self.scale;
+
+            let mut previous = None;
+            for c in line.chars() {
+                pen += self.font.kerning(previous, c) * self.scale;
+                previous = Some(c);
+                let Some(glyph) = self.font.glyph(c) else {
+                    continue;
+                };
+                if glyph.size[0] > 0. && glyph.size[1] > 0. {
+                    let x = pen + glyph.offset[0] * self.scale;
+                    let y = top - (glyph.offset[1] + glyph.size[1]) * self.scale;
+                    instances.push(
+                        SpriteInstance::new(glyph.size, glyph.origin, [x, y])
+                            .with_scale([self.scale; 2])
+                            .with_layer(self.layer)
+                            .with_tint(self.color)
+                            .with_lighting(self.lit),
+                    );
+                }
+                pen += glyph.advance * self.scale;
+            }
+        }
+
+        instances
+    }
+
+    fn block_width(&self, lines: &[String]) -> f32 {
+        self.max_width.unwrap_or_else(|| {
+            lines
+                .iter()
+                .map(|line| self.font.measure(line) * self.scale)
+                .fold(0., f32::max)
+        })
+    }
+
+    // Break lines that are too wide on spaces, or anywhere for single words that don't fit
+    fn lines(&self) -> Vec<String> {
+        let Some(max_width) = self.max_width else {
+            return self.text.lines().map(str::to_owned).collect();
+        };
+        let max_width = max_width / self.scale;
+
+        let mut lines = Vec::new();
+        for paragraph in self.text.lines() {
+            let mut line = String::new();
+            for word in paragraph.split(' ') {
+                let candidate = if line.is_empty() {
+                    word.to_owned()
+                } else {
+                    format!("{line} {word}")
+                };
+                if self.font.measure(&candidate) <= max_width || line.is_empty() {
+                    line = candidate;
+                } else {
+                    lines.push(std::mem::replace(&mut line, word.to_owned()));
+                }
+
+                while self.font.measure(&line) > max_width && line.chars().count() > 1 {
+                    let split = (1..line.len())
+                        .filter(|&i| line.is_char_boundary(i))
+                        .take_while(|&i| self.font.measure(&line[..i]) <= max_width)
+                        .last()
+                        .unwrap_or_else(|| line.chars().next().map_or(1, char::len_utf8));
+                    let rest = line.split_off(split);
+                    lines.push(std::mem::replace(&mut line, rest));
+                }
+            }
+            lines.push(line);
+        }
+
+        lines
+    }
+}
@@ -5,16 +5,41 @@ use super::utils::{PipelineData, UniformData};
 
 const Z_RANGE: f32 = 100.;
 
+/// Pixels drawn past each edge of the view, so shifting the image by the sub-pixel offset when
+/// upscaling never samples outside the render target.
+pub const MARGIN: u32 = 1;
+
 pub struct Camera {
+    // In render target pixels
     height: f32,
     width: f32,
+    // Render target pixels per world unit
     scale: f32,
     offset: (f32, f32),
+    // Round the projection to whole pixels, see [`Camera::subpixel_offset`]
+    pixel_snap: bool,
     proj: Matrix4<f32>,
     pub uniform: UniformData<CameraUniform>,
     pub pipeline_data: PipelineData,
 }
 
+/// Axis aligned rectangle in world space.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Bounds {
+    pub min: [f32; 2],
+    pub max: [f32; 2],
+}
+
+impl Bounds {
+    /// Grow the bounds by `margin` in every direction.
+    pub fn expand(&self, margin: f32) -> Self {
+        Self {
+            min: [self.min[0] - margin, self.min[1] - margin],
+            max: [self.max[0] + margin, self.max[1] + margin],
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct CameraUniform {
@@ -29,17 +54,7 @@ impl Camera {
         scale: f32,
         offset: (f32, f32),
     ) -> Self {
-        let (x, y) = offset;
-        let w = width / scale;
-        let h = height / scale;
-        let proj = cgmath::ortho(
-            (-w / 2.0) + x,
-            (w / 2.0) + x,
-            -(h / 2.0) + y,
-            (h / 2.0) + y,
-            -Z_RANGE,
-            Z_RANGE,
-        );
+        let proj = Self::projection(height, width, scale, offset);
 
         let uniform = CameraUniform {
             view_proj: proj.into(),
@@ -86,42 +101,93 @@ impl Camera {
             uniform: camera_uniform,
             pipeline_data,
             offset,
+            pixel_snap: false,
         }
     }
 
-    pub fn update_view_projection(&mut self, n_h: f32, n_w: f32, d_s: f32) {
-        self.scale += d_s;
-        self.height = n_h;
-        self.width = n_w;
-        let (x, y) = self.offset;
-        let w = self.width / self.scale;
-        let h = self.height / self.scale;
-        self.proj = cgmath::ortho(
+    /// Projection covering the view and its [`MARGIN`], centered on `offset`.
+    fn projection(height: f32, width: f32, scale: f32, offset: (f32, f32)) -> Matrix4<f32> {
+        let (x, y) = offset;
+        let w = (width + 2. * MARGIN as f32) / scale;
+        let h = (height + 2. * MARGIN as f32) / scale;
+        cgmath::ortho(
             (-w / 2.0) + x,
             (w / 2.0) + x,
             -(h / 2.0) + y,
             (h / 2.0) + y,
             -Z_RANGE,
             Z_RANGE,
-        );
-        self.update_view_proj_uniform();
+        )
+    }
+
+    /// Keep the world on the pixel grid of the render target so sprites don't shimmer while
+    /// the camera moves. The fraction that is lost is made up when upscaling.
+    pub fn set_pixel_snap(&mut self, pixel_snap: bool) {
+        self.pixel_snap = pixel_snap;
+        self.update_projection();
+    }
+
+    pub fn scale(&self) -> f32 {
+        self.scale
+    }
+
+    pub fn set_scale(&mut self, scale: f32) {
+        self.scale = scale;
+        self.update_projection();
+    }
+
+    fn snapped_offset(&self) -> (f32, f32) {
+        let (x, y) = self.offset;
+        if !self.pixel_snap {
+            return (x, y);
+        }
+        // Odd sizes have pixel centers, not edges, in the middle of the view
+        let snap = |v: f32, size: f32| {
+            let half = (size % 2.) / 2.;
+            ((v * self.scale - half).round() + half) / self.scale
+        };
+        (snap(x, self.width), snap(y, self.height))
+    }
+
+    /// How far the snapped projection is from the real camera position, in render target
+    /// pixels.
+    pub fn subpixel_offset(&self) -> [f32; 2] {
+        let (x, y) = self.offset;
+        let (snapped_x, snapped_y) = self.snapped_offset();
+        [(x - snapped_x) * self.scale, (y - snapped_y) * self.scale]
+    }
+    pub fn update_view_projection(&mut self, n_h: f32, n_w: f32, d_s: f32) {
+        self.scale += d_s;
+        self.height = n_h;
+        self.width = n_w;
+        self.update_projection();
+    }
+
+    pub fn offset(&self) -> (f32, f32) {
+        self.offset
     }
 
     pub fn move_camera(&mut self, offset: (f32, f32)) {
         self.offset = offset;
-        let (w, h) = (self.width, self.height);
-        let (x, y) = self.offset;
-        self.proj = cgmath::ortho(
-            (-w / 2.0) + x,
-            (w / 2.0) + x,
-            -(h / 2.0) + y,
-            (h / 2.0) + y,
-            -Z_RANGE,
-            Z_RANGE,
-        );
+        self.update_projection();
+    }
+
+    fn update_projection(&mut self) {
+        self.proj = Self::projection(self.height, self.width, self.scale, self.snapped_offset());
         self.update_view_proj_uniform();
     }
 
+    /// The area of the world the camera currently sees.
+    pub fn visible_bounds(&self) -> Bounds {
+        let (x, y) = self.offset;
+        let w = self.width / self.scale;
+        let h = self.height / self.scale;
+        Bounds {
+            min: [x - w / 2.0, y - h / 2.0],
+            max: [x + w / 2.0, y + h / 2.0],
+        }
+    }
+
     pub fn update_view_proj_uniform(&mut self) {
         self.uniform.uniform.view_proj = self.proj.into()
     }
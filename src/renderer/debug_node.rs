@@ -33,6 +33,8 @@ impl DebugNode {
             device,
             &pipeline_layout,
             config.format,
+            Some(wgpu::BlendState::ALPHA_BLENDING),
+            None,
             &[Vertex::desc()],
             wgpu::PrimitiveTopology::TriangleList,
             shader,
@@ -0,0 +1,254 @@
+use wgpu::util::DeviceExt;
+
+use super::{
+    camera::{Camera, MARGIN},
+    lighting::Lighting,
+    pipeline_utils::{
+        create_basic_sampler_bind_group, create_basic_sampler_bind_group_layout,
+        create_render_pipeline,
+    },
+    Texture,
+};
+
+/// Lights the scene in a single full-screen pass.
+///
+/// Sprites and the tilemap write their colour into a G-buffer rather than lighting every
+/// fragment they cover, so overlapping sprites only pay for lighting once. The albedo target
+/// holds premultiplied colour for the lights to act on, the emissive target colour that is
+/// added unchanged, like unlit sprites and the background.
+pub struct DeferredLightingNode {
+    pipeline: wgpu::RenderPipeline,
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    texture_bind_group_layout: wgpu::BindGroupLayout,
+    albedo_bind_group: wgpu::BindGroup,
+    emissive_bind_group: wgpu::BindGroup,
+    pub albedo_texture: Texture,
+    pub emissive_texture: Texture,
+}
+
+impl DeferredLightingNode {
+    /// Format of both G-buffer targets. Additive sprites can push them past 1.
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+    pub fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        sampler: &wgpu::Sampler,
+        lighting: &Lighting,
+    ) -> Self {
+        let (albedo_texture, emissive_texture) =
+            Self::create_targets(device, config.width, config.height);
+
+        let texture_bind_group_layout =
+            create_basic_sampler_bind_group_layout(device, Some("g-buffer bg layout"));
+        let (albedo_bind_group, emissive_bind_group) = Self::create_bind_groups(
+            device,
+            sampler,
+            &texture_bind_group_layout,
+            &albedo_texture,
+            &emissive_texture,
+        );
+
+        // The G-buffer targets each get a group, lighting.wgsl expects to be group 3
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("deferred lighting pipeline layout"),
+            bind_group_layouts: &[
+                &texture_bind_group_layout,
+                &texture_bind_group_layout,
+                &Camera::create_bind_group_layout(device),
+                &lighting.bind_group_layout,
+            ],
+            push_constant_ranges: &[],
+        });
+
+        // Every pixel is written, nothing to blend with
+        let pipeline = create_render_pipeline(
+            device,
+            &pipeline_layout,
+            config.format,
+            None,
+            None,
+            &[Vertex::desc()],
+            wgpu::PrimitiveTopology::TriangleList,
+            wgpu::ShaderModuleDescriptor {
+                label: Some("deferred lighting shader"),
+                source: wgpu::ShaderSource::Wgsl(
+                    [Lighting::SHADER, include_str!("deferred_lighting.wgsl")]
+                        .concat()
+                        .into(),
+                ),
+            },
+            Some("deferred lighting pipeline"),
+        );
+
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("deferred lighting vertex buffer"),
+            contents: bytemuck::cast_slice(VERTICES),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("deferred lighting index buffer"),
+            contents: bytemuck::cast_slice(INDICES),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        Self {
+            pipeline,
+            vertex_buffer,
+            index_buffer,
+            texture_bind_group_layout,
+            albedo_bind_group,
+            emissive_bind_group,
+            albedo_texture,
+            emissive_texture,
+        }
+    }
+
+    /// Color targets of a pipeline writing the G-buffer, albedo at location 0 and emissive at
+    /// location 1. Both are blended the same way.
+    pub fn color_targets(blend: Option<wgpu::BlendState>) -> [Option<wgpu::ColorTargetState>; 2] {
+        let target = Some(wgpu::ColorTargetState {
+            format: Self::FORMAT,
+            blend,
+            write_mask: wgpu::ColorWrites::ALL,
+        });
+        [target.clone(), target]
+    }
+
+    // Sized like the sprite targets, with the camera margin on every side
+    fn create_targets(device: &wgpu::Device, width: u32, height: u32) -> (Texture, Texture) {
+        let create_texture = |label| {
+            Texture::create_2d_texture(
+                device,
+                width + 2 * MARGIN,
+                height + 2 * MARGIN,
+                Self::FORMAT,
+                wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+                Some(label),
+            )
+        };
+        (
+            create_texture("g-buffer albedo texture"),
+            create_texture("g-buffer emissive texture"),
+        )
+    }
+
+    fn create_bind_groups(
+        device: &wgpu::Device,
+        sampler: &wgpu::Sampler,
+        layout: &wgpu::BindGroupLayout,
+        albedo_texture: &Texture,
+        emissive_texture: &Texture,
+    ) -> (wgpu::BindGroup, wgpu::BindGroup) {
+        (
+            create_basic_sampler_bind_group(
+                device,
+                sampler,
+                layout,
+                albedo_texture,
+                Some("g-buffer albedo bg"),
+            ),
+            create_basic_sampler_bind_group(
+                device,
+                sampler,
+                layout,
+                emissive_texture,
+                Some("g-buffer emissive bg"),
+            ),
+        )
+    }
+
+    /// Recreate the G-buffer for a view of `width` by `height`.
+    pub fn resize_targets(
+        &mut self,
+        device: &wgpu::Device,
+        sampler: &wgpu::Sampler,
+        width: u32,
+        height: u32,
+    ) {
+        (self.albedo_texture, self.emissive_texture) = Self::create_targets(device, width, height);
+        (self.albedo_bind_group, self.emissive_bind_group) = Self::create_bind_groups(
+            device,
+            sampler,
+            &self.texture_bind_group_layout,
+            &self.albedo_texture,
+            &self.emissive_texture,
+        );
+    }
+}
+
+//TODO: Move this to somewhere general
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct Vertex {
+    position: [f32; 2],
+    tex_coords: [f32; 2],
+}
+
+impl Vertex {
+    const ATTRIBS: [wgpu::VertexAttribute; 2] =
+        wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x2];
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+const VERTICES: &[Vertex] = &[
+    Vertex {
+        position: [-1.0, 1.0],
+        tex_coords: [0.0, 0.0],
+    }, // Top-left
+    Vertex {
+        position: [1.0, 1.0],
+        tex_coords: [1.0, 0.0],
+    }, // Top-right
+    Vertex {
+        position: [-1.0, -1.0],
+        tex_coords: [0.0, 1.0],
+    }, // Bottom-left
+    Vertex {
+        position: [1.0, -1.0],
+        tex_coords: [1.0, 1.0],
+    }, // Bottom-right
+];
+
+const INDICES: &[u16] = &[2, 1, 0u16, 2, 3, 1];
+
+pub(super) trait DrawLighting<'a> {
+    fn draw_lighting(
+        &mut self,
+        lighting_renderer: &'a DeferredLightingNode,
+        camera: &'a Camera,
+        lighting: &'a Lighting,
+    );
+}
+
+impl<'a, 'b> DrawLighting<'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_lighting(
+        &mut self,
+        lighting_renderer: &'b DeferredLightingNode,
+        camera: &'b Camera,
+        lighting: &'b Lighting,
+    ) {
+        self.set_pipeline(&lighting_renderer.pipeline);
+        self.set_vertex_buffer(0, lighting_renderer.vertex_buffer.slice(..));
+        self.set_index_buffer(
+            lighting_renderer.index_buffer.slice(..),
+            wgpu::IndexFormat::Uint16,
+        );
+        self.set_bind_group(0, &lighting_renderer.albedo_bind_group, &[]);
+        self.set_bind_group(1, &lighting_renderer.emissive_bind_group, &[]);
+        self.set_bind_group(2, camera.bind_group(), &[]);
+        self.set_bind_group(3, &lighting.bind_group, &[]);
+        self.draw_indexed(0..INDICES.len() as u32, 0, 0..1)
+    }
+}
@@ -0,0 +1,64 @@
+// Lights the G-buffer in one pass over the whole render target, see DeferredLightingNode.
+// Concatenated after the lighting shader.
+
+struct VertexInput {
+    @location(0) position: vec2<f32>,
+	@location(1) tex_coords: vec2<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+	@location(0) tex_coords: vec2<f32>,
+}
+
+struct CameraUniform {
+    view_proj: mat4x4<f32>
+};
+
+// Premultiplied colour of the lit sprites and tiles
+@group(0) @binding(0)
+var albedo_texture: texture_2d<f32>;
+
+@group(0) @binding(1)
+var albedo_sampler: sampler;
+
+// Premultiplied colour of everything unlit, added after lighting
+@group(1) @binding(0)
+var emissive_texture: texture_2d<f32>;
+
+@group(1) @binding(1)
+var emissive_sampler: sampler;
+
+@group(2) @binding(0)
+var<uniform> camera: CameraUniform;
+
+@vertex
+fn vs_main(input: VertexInput) -> VertexOutput {
+	var out: VertexOutput;
+	out.clip_position = vec4(input.position, 0.0, 1.0);
+	out.tex_coords = input.tex_coords;
+    return out;
+}
+
+// Undo the camera's orthographic projection for a point on the render target
+fn world_position(tex_coords: vec2<f32>) -> vec2<f32> {
+	let clip = vec2(tex_coords.x * 2.0 - 1.0, 1.0 - tex_coords.y * 2.0);
+	let view_proj = camera.view_proj;
+	return (clip - view_proj[3].xy) / vec2(view_proj[0].x, view_proj[1].y);
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+	let albedo = textureSample(albedo_texture, albedo_sampler, in.tex_coords);
+	let emissive = textureSample(emissive_texture, emissive_sampler, in.tex_coords);
+
+	// Nothing lit here, skip the raymarching
+	if (all(albedo.rgb == vec3(0.0))) {
+		return vec4(emissive.rgb, 1.0);
+	}
+
+	// Lighting is linear in the base colour, so lighting the blended albedo comes out the
+	// same as lighting every sprite before blending it
+	let lit = apply_lighting(albedo.rgb, world_position(in.tex_coords));
+	return vec4(lit + emissive.rgb, 1.0);
+}
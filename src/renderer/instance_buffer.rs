@@ -0,0 +1,159 @@
+use std::{marker::PhantomData, ops::Range};
+
+use anyhow::bail;
+use bytemuck::Pod;
+
+/// A vertex buffer of instances that grows when more instances are written than fit.
+///
+/// Growing reallocates the buffer and copies the old contents over on the GPU, so callers
+/// only need to re-set the vertex buffer, which happens every draw anyway.
+pub struct InstanceBuffer<T> {
+    buffer: wgpu::Buffer,
+    label: &'static str,
+    // Capacity and length are counted in instances, not bytes
+    capacity: usize,
+    len: usize,
+    max_capacity: usize,
+    _instance: PhantomData<T>,
+}
+
+impl<T: Pod> InstanceBuffer<T> {
+    const STRIDE: u64 = std::mem::size_of::<T>() as u64;
+
+    pub fn new(
+        device: &wgpu::Device,
+        label: &'static str,
+        capacity: usize,
+        max_capacity: usize,
+    ) -> Self {
+        // Can't go beyond what the device allows for a single buffer
+        let max_capacity =
+            max_capacity.min((device.limits().max_buffer_size / Self::STRIDE) as usize);
+        let capacity = capacity.clamp(1, max_capacity);
+
+        Self {
+            buffer: Self::create_buffer(device, label, capacity),
+            label,
+            capacity,
+            len: 0,
+            max_capacity,
+            _instance: PhantomData,
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, label: &str, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: capacity as u64 * Self::STRIDE,
+            usage: wgpu::BufferUsages::VERTEX
+                | wgpu::BufferUsages::COPY_DST
+                | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Replace the contents of the buffer, growing it if needed.
+    pub fn write(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        instances: &[T],
+    ) -> anyhow::Result<()> {
+        self.reserve(device, queue, instances.len())?;
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(instances));
+        self.len = instances.len();
+
+        Ok(())
+    }
+
+    /// Write only the given ranges of `instances`, the rest of the buffer is assumed to
+    /// already match. Growing keeps the old contents, so the ranges are enough there too.
+    pub fn write_ranges(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        instances: &[T],
+        ranges: &[Range<usize>],
+    ) -> anyhow::Result<()> {
+        self.reserve(device, queue, instances.len())?;
+        for range in ranges {
+            let range = range.start.min(instances.len())..range.end.min(instances.len());
+            queue.write_buffer(
+                &self.buffer,
+                range.start as u64 * Self::STRIDE,
+                bytemuck::cast_slice(&instances[range]),
+            );
+        }
+        self.len = instances.len();
+
+        Ok(())
+    }
+
+    /// Make sure the buffer can hold at least `capacity` instances.
+    pub fn reserve(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        capacity: usize,
+    ) -> anyhow::Result<()> {
+        if capacity <= self.capacity {
+            return Ok(());
+        }
+        if capacity > self.max_capacity {
+            bail!(
+                "{}: {} instances exceeds the limit of {} instances",
+                self.label,
+                capacity,
+                self.max_capacity
+            );
+        }
+
+        let new_capacity = capacity.next_power_of_two().min(self.max_capacity);
+        self.reallocate(device, queue, new_capacity);
+
+        Ok(())
+    }
+
+    /// Shrink the buffer to fit the instances currently in it, or to `min_capacity` if
+    /// that is larger.
+    pub fn shrink_to(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, min_capacity: usize) {
+        let new_capacity = self.len.max(min_capacity).max(1);
+        if new_capacity < self.capacity {
+            self.reallocate(device, queue, new_capacity);
+        }
+    }
+
+    fn reallocate(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, capacity: usize) {
+        let buffer = Self::create_buffer(device, self.label, capacity);
+
+        let copy_size = (self.len.min(capacity) as u64) * Self::STRIDE;
+        if copy_size > 0 {
+            let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("instance buffer reallocation"),
+            });
+            encoder.copy_buffer_to_buffer(&self.buffer, 0, &buffer, 0, copy_size);
+            queue.submit(Some(encoder.finish()));
+        }
+
+        self.buffer = buffer;
+        self.capacity = capacity;
+        self.len = self.len.min(capacity);
+    }
+
+    pub fn slice(&self) -> wgpu::BufferSlice<'_> {
+        self.buffer.slice(..self.len.max(1) as u64 * Self::STRIDE)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Most instances the buffer can grow to.
+    pub fn max_capacity(&self) -> usize {
+        self.max_capacity
+    }
+}
@@ -0,0 +1,277 @@
+use super::camera::{Camera, MARGIN};
+
+/// Bins lights into a grid of tiles laid over the view, so fragments only raymarch the
+/// lights that can reach their tile. Shares its buffers with [`super::Lighting`], which
+/// binds the tiles for the fragment shaders.
+pub struct LightCulling {
+    pipeline: wgpu::ComputePipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    tiles_buffer: wgpu::Buffer,
+    grid_buffer: wgpu::Buffer,
+    tiles: [u32; 2],
+}
+
+impl LightCulling {
+    pub const SHADER: &'static str = concat!(
+        include_str!("light_types.wgsl"),
+        include_str!("light_culling.wgsl")
+    );
+    /// Size of a tile in render target pixels.
+    pub const TILE_PIXELS: u32 = 16;
+    /// Lights beyond this many in one tile are dropped.
+    pub const MAX_LIGHTS_PER_TILE: usize = 31;
+    const WORKGROUP_SIZE: u32 = 8;
+
+    /// Set up a grid covering a `width` by `height` view.
+    pub fn new(
+        device: &wgpu::Device,
+        lights_buffer: &wgpu::Buffer,
+        light_count_buffer: &wgpu::Buffer,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let tiles = Self::tile_count(width, height);
+        let tiles_buffer = Self::create_tiles_buffer(device, tiles);
+        let grid_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Light Grid Buffer"),
+            size: std::mem::size_of::<GpuLightGrid>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let buffer_entry = |binding, ty| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("light culling bind group layout"),
+            entries: &[
+                buffer_entry(0, wgpu::BufferBindingType::Storage { read_only: true }),
+                buffer_entry(1, wgpu::BufferBindingType::Uniform),
+                buffer_entry(2, wgpu::BufferBindingType::Storage { read_only: false }),
+                buffer_entry(3, wgpu::BufferBindingType::Uniform),
+            ],
+        });
+        let bind_group = Self::create_bind_group(
+            device,
+            &bind_group_layout,
+            [
+                lights_buffer,
+                light_count_buffer,
+                &tiles_buffer,
+                &grid_buffer,
+            ],
+        );
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("light culling shader module"),
+            source: wgpu::ShaderSource::Wgsl(Self::SHADER.into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("light culling pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("light culling compute pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "main",
+        });
+
+        Self {
+            pipeline,
+            bind_group_layout,
+            bind_group,
+            tiles_buffer,
+            grid_buffer,
+            tiles,
+        }
+    }
+
+    // Tiles covering the render target, which includes the camera's margin
+    fn tile_count(width: u32, height: u32) -> [u32; 2] {
+        [
+            (width + 2 * MARGIN).div_ceil(Self::TILE_PIXELS),
+            (height + 2 * MARGIN).div_ceil(Self::TILE_PIXELS),
+        ]
+    }
+
+    // Starts zeroed, so every tile is empty until the first cull
+    fn create_tiles_buffer(device: &wgpu::Device, [x, y]: [u32; 2]) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Tile Lights Buffer"),
+            size: (x * y) as u64 * TILE_LIGHTS_SIZE as u64,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        })
+    }
+
+    // The lights, light count, tile lights and grid buffers, in that order
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        buffers: [&wgpu::Buffer; 4],
+    ) -> wgpu::BindGroup {
+        let entries: Vec<_> = buffers
+            .iter()
+            .enumerate()
+            .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                binding: binding as u32,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect();
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("light culling bind group"),
+            layout,
+            entries: &entries,
+        })
+    }
+
+    /// Read lights from a new buffer, after the lights outgrew the old one.
+    pub fn set_lights(
+        &mut self,
+        device: &wgpu::Device,
+        lights_buffer: &wgpu::Buffer,
+        light_count_buffer: &wgpu::Buffer,
+    ) {
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            [
+                lights_buffer,
+                light_count_buffer,
+                &self.tiles_buffer,
+                &self.grid_buffer,
+            ],
+        );
+    }
+
+    /// Cover a view of a new size. This replaces the tile buffer, so bind groups made from
+    /// [`Self::bind_group_entries`] have to be recreated.
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        lights_buffer: &wgpu::Buffer,
+        light_count_buffer: &wgpu::Buffer,
+        width: u32,
+        height: u32,
+    ) {
+        self.tiles = Self::tile_count(width, height);
+        self.tiles_buffer = Self::create_tiles_buffer(device, self.tiles);
+        self.set_lights(device, lights_buffer, light_count_buffer);
+    }
+
+    /// Lay the grid over what `camera` sees and bin the lights into it.
+    pub fn cull(&self, encoder: &mut wgpu::CommandEncoder, queue: &wgpu::Queue, camera: &Camera) {
+        let bounds = camera
+            .visible_bounds()
+            .expand(MARGIN as f32 / camera.scale());
+        let grid = GpuLightGrid {
+            origin: bounds.min,
+            tile_size: Self::TILE_PIXELS as f32 / camera.scale(),
+            _padding: 0,
+            tiles: self.tiles,
+        };
+        queue.write_buffer(&self.grid_buffer, 0, bytemuck::cast_slice(&[grid]));
+
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("light culling"),
+            timestamp_writes: None,
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &self.bind_group, &[]);
+        let [x, y] = self.tiles;
+        pass.dispatch_workgroups(
+            x.div_ceil(Self::WORKGROUP_SIZE),
+            y.div_ceil(Self::WORKGROUP_SIZE),
+            1,
+        );
+    }
+
+    pub fn bind_group_layout_entries() -> [wgpu::BindGroupLayoutEntry; 2] {
+        [
+            wgpu::BindGroupLayoutEntry {
+                binding: 5,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 6,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ]
+    }
+
+    pub fn bind_group_entries(&self) -> [wgpu::BindGroupEntry<'_>; 2] {
+        [
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: self.tiles_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 6,
+                resource: self.grid_buffer.as_entire_binding(),
+            },
+        ]
+    }
+}
+
+// Size of a TileLights in light_types.wgsl, they only ever exist on the GPU
+const TILE_LIGHTS_SIZE: usize = (1 + LightCulling::MAX_LIGHTS_PER_TILE) * 4;
+
+// Matches LightGrid in light_types.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct GpuLightGrid {
+    origin: [f32; 2],
+    tile_size: f32,
+    _padding: u32,
+    tiles: [u32; 2],
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::{offset_of, size_of};
+
+    use super::*;
+    use crate::renderer::lighting::tests::wgsl_layout;
+
+    #[test]
+    fn light_grid_matches_wgsl() {
+        let (size, members) = wgsl_layout(LightCulling::SHADER, "LightGrid");
+        assert_eq!(size, size_of::<GpuLightGrid>());
+        assert_eq!(
+            members,
+            [
+                ("origin".into(), offset_of!(GpuLightGrid, origin)),
+                ("tile_size".into(), offset_of!(GpuLightGrid, tile_size)),
+                ("tiles".into(), offset_of!(GpuLightGrid, tiles)),
+            ]
+        );
+    }
+
+    #[test]
+    fn tile_lights_matches_wgsl() {
+        let (size, _) = wgsl_layout(LightCulling::SHADER, "TileLights");
+        assert_eq!(size, TILE_LIGHTS_SIZE);
+    }
+}
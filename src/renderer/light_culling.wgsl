@@ -0,0 +1,59 @@
+// Bins lights into the tiles of the light grid, concatenated after light_types.wgsl. See
+// LightCulling.
+
+@group(0) @binding(0)
+var<storage, read> lights: array<Light>;
+
+@group(0) @binding(1)
+var<uniform> light_count: u32;
+
+@group(0) @binding(2)
+var<storage, read_write> tile_lights: array<TileLights>;
+
+@group(0) @binding(3)
+var<uniform> light_grid: LightGrid;
+
+// Lights are left out of tiles where they would add less than this
+const LIGHT_CUTOFF: f32 = 0.01;
+
+// Corners of the box a light can reach, min in xy and max in zw
+fn light_bounds(light: Light) -> vec4<f32> {
+	// Where the falloff in apply_lighting drops to LIGHT_CUTOFF
+	let reach = sqrt(max(light.intensity * 100. / LIGHT_CUTOFF - 40., 0.) / max(light.falloff, 0.0001));
+
+	var lo = light.position;
+	var hi = light.position;
+	switch light.kind {
+		case LINE_LIGHT: {
+			lo = min(light.position, light.position + light.extent);
+			hi = max(light.position, light.position + light.extent);
+		}
+		case RECT_LIGHT: {
+			lo = light.position - abs(light.extent);
+			hi = light.position + abs(light.extent);
+		}
+		default: {}
+	}
+	return vec4(lo - reach, hi + reach);
+}
+
+@compute @workgroup_size(8, 8)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+	if (id.x >= light_grid.tiles.x || id.y >= light_grid.tiles.y) {
+		return;
+	}
+	let tile_min = light_grid.origin + vec2<f32>(id.xy) * light_grid.tile_size;
+	let tile_max = tile_min + light_grid.tile_size;
+	let tile = id.y * light_grid.tiles.x + id.x;
+
+	// Lights past the first MAX_LIGHTS_PER_TILE are dropped
+	var count = 0u;
+	for (var i: u32 = 0u; i < light_count && count < MAX_LIGHTS_PER_TILE; i = i + 1u) {
+		let bounds = light_bounds(lights[i]);
+		if (all(bounds.xy <= tile_max) && all(bounds.zw >= tile_min)) {
+			tile_lights[tile].lights[count] = i;
+			count = count + 1u;
+		}
+	}
+	tile_lights[tile].count = count;
+}
@@ -0,0 +1,38 @@
+// Light data shared by the lighting and light culling shaders.
+
+// Must match the kinds in lighting.rs
+const POINT_LIGHT: u32 = 0u;
+const SPOT_LIGHT: u32 = 1u;
+const LINE_LIGHT: u32 = 2u;
+const RECT_LIGHT: u32 = 3u;
+
+struct Light {
+	position: vec2<f32>,
+	intensity: f32,
+	falloff: f32,
+	color: vec3<f32>,
+	kind: u32,
+	// Spot lights only, the cone's axis and the cosines of its inner and outer angles
+	direction: vec2<f32>,
+	cos_inner: f32,
+	cos_outer: f32,
+	// Line and rect lights only, see light_sample in lighting.wgsl
+	extent: vec2<f32>,
+	samples: u32,
+};
+
+// Must match LightCulling::MAX_LIGHTS_PER_TILE
+const MAX_LIGHTS_PER_TILE: u32 = 31u;
+
+// Indices of the lights that reach a tile
+struct TileLights {
+	count: u32,
+	lights: array<u32, MAX_LIGHTS_PER_TILE>,
+};
+
+// Tiles of `tile_size` world units, laid over the view from `origin`
+struct LightGrid {
+	origin: vec2<f32>,
+	tile_size: f32,
+	tiles: vec2<u32>,
+};
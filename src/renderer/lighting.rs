@@ -0,0 +1,451 @@
+use std::ops::Range;
+
+use anyhow::bail;
+
+use super::{light_culling::LightCulling, Camera, Texture};
+
+/// Lights and the SDF they are raymarched against, shared by every pipeline that lights
+/// its fragments. Shaders using it are concatenated with [`Self::SHADER`] and bind it as
+/// group 3.
+pub struct Lighting {
+    // Grows with the light list, only the first `light_count` lights are read
+    lights_buffer: wgpu::Buffer,
+    light_capacity: usize,
+    // Last count written to light_count_buffer
+    light_count: usize,
+    light_count_buffer: wgpu::Buffer,
+    // Written on the GPU by the particle pass, see ParticleNode
+    pub particle_lights_buffer: wgpu::Buffer,
+    pub bind_group_layout: wgpu::BindGroupLayout,
+    pub bind_group: wgpu::BindGroup,
+    culling: LightCulling,
+}
+
+impl Lighting {
+    pub const SHADER: &'static str = concat!(
+        include_str!("light_types.wgsl"),
+        include_str!("lighting.wgsl")
+    );
+    /// Particle lights are unshadowed and every fragment loops over all of them.
+    pub const MAX_PARTICLE_LIGHTS: usize = 64;
+    /// Upper bound for the samples of line lights, and per side of rect lights.
+    pub const MAX_LIGHT_SAMPLES: u32 = 16;
+    const INITIAL_LIGHT_CAPACITY: usize = 16;
+
+    /// Lighting for a `width` by `height` view, see [`LightCulling`].
+    pub fn new(
+        device: &wgpu::Device,
+        sampler: &wgpu::Sampler,
+        sdf_texture: &Texture,
+        width: u32,
+        height: u32,
+    ) -> Self {
+        let light_capacity = Self::INITIAL_LIGHT_CAPACITY;
+        let lights_buffer = Self::create_lights_buffer(device, light_capacity);
+        // Starts zeroed, so no lights are read until the first draw_lights
+        let light_count_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Light Count Buffer"),
+            size: 16,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        // Starts zeroed, which switches every particle light off
+        let particle_lights_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Particle Lights Buffer"),
+            size: (Self::MAX_PARTICLE_LIGHTS * PARTICLE_LIGHT_SIZE) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let culling = LightCulling::new(device, &lights_buffer, &light_count_buffer, width, height);
+
+        let [tiles_entry, grid_entry] = LightCulling::bind_group_layout_entries();
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("lights bind group layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                tiles_entry,
+                grid_entry,
+            ],
+        });
+
+        let bind_group = Self::create_bind_group(
+            device,
+            &bind_group_layout,
+            [&lights_buffer, &particle_lights_buffer, &light_count_buffer],
+            &culling,
+            sampler,
+            sdf_texture,
+        );
+
+        Self {
+            lights_buffer,
+            light_capacity,
+            light_count: 0,
+            light_count_buffer,
+            particle_lights_buffer,
+            bind_group_layout,
+            bind_group,
+            culling,
+        }
+    }
+
+    // The lights, particle lights and light count buffers, in that order
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        [lights, particle_lights, light_count]: [&wgpu::Buffer; 3],
+        culling: &LightCulling,
+        sampler: &wgpu::Sampler,
+        sdf_texture: &Texture,
+    ) -> wgpu::BindGroup {
+        let [tiles_entry, grid_entry] = culling.bind_group_entries();
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("lights bind group"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: lights.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&sdf_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: particle_lights.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: light_count.as_entire_binding(),
+                },
+                tiles_entry,
+                grid_entry,
+            ],
+        })
+    }
+
+    fn create_lights_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Lights Buffer"),
+            size: (capacity * std::mem::size_of::<GpuLight>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Upload the ranges of `lights` that changed since the last call, growing the buffer
+    /// if there are more than fit. Growing starts a new buffer, so every light is uploaded
+    /// then, and creates a new bind group, which needs the sampler and SDF texture the
+    /// lighting was created with.
+    pub fn draw_lights(
+        &mut self,
+        lights: &[Light],
+        dirty: &[Range<usize>],
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        sampler: &wgpu::Sampler,
+        sdf_texture: &Texture,
+    ) -> anyhow::Result<()> {
+        if lights.len() > self.light_capacity {
+            let max_lights = device.limits().max_storage_buffer_binding_size as usize
+                / std::mem::size_of::<GpuLight>();
+            if lights.len() > max_lights {
+                bail!(
+                    "{} lights exceeds the limit of {} lights",
+                    lights.len(),
+                    max_lights
+                );
+            }
+            self.light_capacity = lights.len().next_power_of_two().min(max_lights);
+            self.lights_buffer = Self::create_lights_buffer(device, self.light_capacity);
+            self.culling
+                .set_lights(device, &self.lights_buffer, &self.light_count_buffer);
+            self.recreate_bind_group(device, sampler, sdf_texture);
+            self.write_lights(queue, lights, 0..lights.len());
+        } else {
+            for range in dirty {
+                let range = range.start.min(lights.len())..range.end.min(lights.len());
+                self.write_lights(queue, lights, range);
+            }
+        }
+        if lights.len() != self.light_count {
+            self.light_count = lights.len();
+            queue.write_buffer(
+                &self.light_count_buffer,
+                0,
+                bytemuck::cast_slice(&[self.light_count as u32]),
+            );
+        }
+
+        Ok(())
+    }
+
+    fn write_lights(&self, queue: &wgpu::Queue, lights: &[Light], range: Range<usize>) {
+        if range.is_empty() {
+            return;
+        }
+        let gpu_lights: Vec<GpuLight> = lights[range.clone()].iter().map(GpuLight::from).collect();
+        queue.write_buffer(
+            &self.lights_buffer,
+            (range.start * std::mem::size_of::<GpuLight>()) as u64,
+            bytemuck::cast_slice(&gpu_lights),
+        );
+    }
+
+    fn recreate_bind_group(
+        &mut self,
+        device: &wgpu::Device,
+        sampler: &wgpu::Sampler,
+        sdf_texture: &Texture,
+    ) {
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            [
+                &self.lights_buffer,
+                &self.particle_lights_buffer,
+                &self.light_count_buffer,
+            ],
+            &self.culling,
+            sampler,
+            sdf_texture,
+        );
+    }
+
+    /// Cover a view of a new size with the light grid.
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        sampler: &wgpu::Sampler,
+        sdf_texture: &Texture,
+        width: u32,
+        height: u32,
+    ) {
+        self.culling.resize(
+            device,
+            &self.lights_buffer,
+            &self.light_count_buffer,
+            width,
+            height,
+        );
+        self.recreate_bind_group(device, sampler, sdf_texture);
+    }
+
+    /// Bin the lights into the tiles of what `camera` sees. Has to run before anything lit
+    /// is drawn from that camera.
+    pub fn cull(&self, encoder: &mut wgpu::CommandEncoder, queue: &wgpu::Queue, camera: &Camera) {
+        self.culling.cull(encoder, queue, camera);
+    }
+}
+
+// Size of a ParticleLight in lighting.wgsl, they only ever exist on the GPU
+const PARTICLE_LIGHT_SIZE: usize = 32;
+
+/// A shadow casting light, raymarched against the SDF for every lit fragment.
+#[derive(Copy, Clone, Debug)]
+pub struct Light {
+    pub position: [f32; 2],
+    pub intensity: f32,
+    pub falloff: f32,
+    pub color: [f32; 3],
+    pub shape: LightShape,
+}
+
+/// Which way a [`Light`] shines.
+///
+/// Line and rect lights are approximated by `samples` point lights spread over the shape,
+/// each raymarched on its own. More samples give smoother light and shadows, at the cost of
+/// a raymarch per sample for every lit fragment.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LightShape {
+    /// Equally in every direction
+    Point,
+    /// In a cone around `direction`, in radians counter-clockwise from +x. Angles are
+    /// measured from the middle of the cone, the light is at full strength up to
+    /// `inner_angle` and fades out smoothly towards `outer_angle`.
+    Spot {
+        direction: f32,
+        inner_angle: f32,
+        outer_angle: f32,
+    },
+    /// Along the segment from `position` to `position + offset`, for neon tubes and
+    /// glowing strips.
+    Line { offset: [f32; 2], samples: u32 },
+    /// Over a rectangle of `size` centered on `position`, for windows and light panels.
+    /// `samples` is per side.
+    Rect { size: [f32; 2], samples: u32 },
+}
+
+// Light kinds in lighting.wgsl
+const POINT_LIGHT: u32 = 0;
+const SPOT_LIGHT: u32 = 1;
+const LINE_LIGHT: u32 = 2;
+const RECT_LIGHT: u32 = 3;
+
+// Matches Light in lighting.wgsl, where the vec3 color is aligned to 16 bytes
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct GpuLight {
+    position: [f32; 2],
+    intensity: f32,
+    falloff: f32,
+    color: [f32; 3],
+    kind: u32,
+    direction: [f32; 2],
+    // Cosines of the cone angles, so the shader compares against a dot product
+    cos_inner: f32,
+    cos_outer: f32,
+    // Line lights run along `extent`, rect lights reach `extent` from the center
+    extent: [f32; 2],
+    samples: u32,
+    _padding: u32,
+}
+
+impl From<&Light> for GpuLight {
+    fn from(light: &Light) -> Self {
+        let mut gpu_light = Self {
+            position: light.position,
+            intensity: light.intensity,
+            falloff: light.falloff,
+            color: light.color,
+            kind: POINT_LIGHT,
+            direction: [0., 0.],
+            cos_inner: -1.,
+            cos_outer: -1.,
+            extent: [0., 0.],
+            samples: 1,
+            _padding: 0,
+        };
+        let samples = |samples: u32| samples.clamp(1, Lighting::MAX_LIGHT_SAMPLES);
+        match light.shape {
+            LightShape::Point => {}
+            LightShape::Spot {
+                direction,
+                inner_angle,
+                outer_angle,
+            } => {
+                gpu_light.kind = SPOT_LIGHT;
+                gpu_light.direction = [direction.cos(), direction.sin()];
+                gpu_light.cos_inner = inner_angle.cos();
+                // Keep the edge from collapsing, smoothstep needs the cosines to differ
+                gpu_light.cos_outer = outer_angle.max(inner_angle + 1e-3).cos();
+            }
+            LightShape::Line { offset, samples: n } => {
+                gpu_light.kind = LINE_LIGHT;
+                gpu_light.extent = offset;
+                gpu_light.samples = samples(n);
+            }
+            LightShape::Rect { size, samples: n } => {
+                gpu_light.kind = RECT_LIGHT;
+                gpu_light.extent = [size[0] / 2., size[1] / 2.];
+                gpu_light.samples = samples(n);
+            }
+        }
+        gpu_light
+    }
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use std::mem::{offset_of, size_of};
+
+    use super::*;
+
+    // Size and member offsets of a struct in `shader`
+    pub(in crate::renderer) fn wgsl_layout(
+        shader: &str,
+        name: &str,
+    ) -> (usize, Vec<(String, usize)>) {
+        let module = naga::front::wgsl::parse_str(shader).unwrap();
+        let (_, ty) = module
+            .types
+            .iter()
+            .find(|(_, ty)| ty.name.as_deref() == Some(name))
+            .unwrap_or_else(|| panic!("no struct {name} in the shader"));
+        let naga::TypeInner::Struct { members, span } = &ty.inner else {
+            panic!("{name} is not a struct");
+        };
+        let members = members
+            .iter()
+            .map(|m| (m.name.clone().unwrap(), m.offset as usize))
+            .collect();
+        (*span as usize, members)
+    }
+
+    #[test]
+    fn light_matches_wgsl() {
+        let (size, members) = wgsl_layout(Lighting::SHADER, "Light");
+        assert_eq!(size, size_of::<GpuLight>());
+        assert_eq!(
+            members,
+            [
+                ("position".into(), offset_of!(GpuLight, position)),
+                ("intensity".into(), offset_of!(GpuLight, intensity)),
+                ("falloff".into(), offset_of!(GpuLight, falloff)),
+                ("color".into(), offset_of!(GpuLight, color)),
+                ("kind".into(), offset_of!(GpuLight, kind)),
+                ("direction".into(), offset_of!(GpuLight, direction)),
+                ("cos_inner".into(), offset_of!(GpuLight, cos_inner)),
+                ("cos_outer".into(), offset_of!(GpuLight, cos_outer)),
+                ("extent".into(), offset_of!(GpuLight, extent)),
+                ("samples".into(), offset_of!(GpuLight, samples)),
+            ]
+        );
+    }
+
+    #[test]
+    fn particle_light_matches_wgsl() {
+        let (size, _) = wgsl_layout(Lighting::SHADER, "ParticleLight");
+        assert_eq!(size, PARTICLE_LIGHT_SIZE);
+    }
+}
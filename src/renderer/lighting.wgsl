@@ -0,0 +1,176 @@
+// Shared lighting, concatenated in front of the shaders that use it after light_types.wgsl.
+// See Lighting.
+
+@group(3) @binding(0)
+var<storage, read> lights: array<Light>;
+
+@group(3) @binding(1)
+var sdf_texture: texture_2d<f32>;
+
+@group(3) @binding(2)
+var sdf_sampler: sampler;
+
+// Small lights carried by particles. They don't cast shadows, so they're cheap enough to
+// have lots of. Written by the particle compute pass, unused slots have zero intensity.
+struct ParticleLight {
+	position: vec2<f32>,
+	intensity: f32,
+	falloff: f32,
+	color: vec4<f32>,
+}
+
+// Must match Lighting::MAX_PARTICLE_LIGHTS
+const MAX_PARTICLE_LIGHTS: i32 = 64;
+
+@group(3) @binding(3)
+var<storage, read> particle_lights: array<ParticleLight>;
+
+// Only the first light_count lights are in use, the storage buffer has room to grow
+@group(3) @binding(4)
+var<uniform> light_count: u32;
+
+// Lights binned into tiles by the culling pass, see light_culling.wgsl
+@group(3) @binding(5)
+var<storage, read> tile_lights: array<TileLights>;
+
+@group(3) @binding(6)
+var<uniform> light_grid: LightGrid;
+
+//TODO: uniform
+const screen = vec2(1920., 1200.);
+
+// How much of a light at `light_pos` reaches `w_p`, 0 when a wall is in the way and less
+// than 1 in the penumbra
+fn soft_shadow(w_p: vec2<f32>, light_pos: vec2<f32>) -> f32 {
+	let light_dir = normalize(light_pos - w_p);
+	let dist = length(light_pos - w_p);
+	var dist_traveled = 0.0;
+
+	// soft shadows
+	var s = 1.0;
+	let k = 12.0;
+
+	// Raymarch a light.
+	for (var j: i32 = 0; j < 100; j = j + 1) {
+		// march from fragment to wards light. sample sdf to determine step distance
+		let d = textureSampleLevel(sdf_texture, sdf_sampler, (w_p + (dist_traveled * light_dir)) / screen, 0.0).r;
+
+		// hit a wall
+		if (d < 0.00001) {
+			return 0.0;
+		}
+
+		dist_traveled += d;
+
+		// calculate soft shadows
+		s = min(s, (k * d) / dist_traveled);
+
+		// overshot the light source
+		if (dist_traveled >= dist) {
+			break;
+		};
+	}
+	return s;
+}
+
+// Number of points a light is sampled at
+fn light_samples(light: Light) -> u32 {
+	switch light.kind {
+		case LINE_LIGHT: {
+			return light.samples;
+		}
+		case RECT_LIGHT: {
+			return light.samples * light.samples;
+		}
+		default: {
+			return 1u;
+		}
+	}
+}
+
+// Position of sample `k`, spread evenly over the shape of the light
+fn light_sample(light: Light, k: u32) -> vec2<f32> {
+	switch light.kind {
+		case LINE_LIGHT: {
+			let t = (f32(k) + 0.5) / f32(light.samples);
+			return light.position + light.extent * t;
+		}
+		case RECT_LIGHT: {
+			let cell = vec2(f32(k % light.samples), f32(k / light.samples));
+			let uv = (cell + 0.5) / f32(light.samples);
+			return light.position + light.extent * (uv * 2. - 1.);
+		}
+		default: {
+			return light.position;
+		}
+	}
+}
+
+// Index of the tile `w_p` is in, or -1 outside the grid
+fn light_tile(w_p: vec2<f32>) -> i32 {
+	let cell = vec2<i32>(floor((w_p - light_grid.origin) / light_grid.tile_size));
+	if (any(cell < vec2(0)) || any(cell >= vec2<i32>(light_grid.tiles))) {
+		return -1;
+	}
+	return cell.y * i32(light_grid.tiles.x) + cell.x;
+}
+
+// Light a fragment with base color `base` at world position `w_p`
+fn apply_lighting(base: vec3<f32>, w_p: vec2<f32>) -> vec3<f32> {
+	// make everything dark
+	let ambient_light = vec3(0.015, 0.015, 0.015);
+	var final_color = base * ambient_light;
+
+	// Only the lights that reach the tile, or every light outside the grid
+	let tile = light_tile(w_p);
+	var count = light_count;
+	if (tile >= 0) {
+		count = tile_lights[tile].count;
+	}
+	for (var j: u32 = 0u; j < count; j = j + 1u) {
+		var i = j;
+		if (tile >= 0) {
+			i = tile_lights[tile].lights[j];
+		}
+		let light = lights[i];
+
+		var cone = 1.0;
+		let to_fragment = w_p - light.position;
+		// A fragment right at the light has no direction, treat it as inside the cone
+		if (light.kind == SPOT_LIGHT && length(to_fragment) >= 1e-4) {
+			cone = smoothstep(light.cos_outer, light.cos_inner, dot(normalize(to_fragment), light.direction));
+			// No point raymarching what the cone doesn't reach
+			if (cone <= 0.0) {
+				continue;
+			}
+		}
+
+		// Every sample carries an equal share of the intensity
+		let samples = light_samples(light);
+		var received = 0.0;
+		for (var k: u32 = 0u; k < samples; k = k + 1u) {
+			let sample_pos = light_sample(light, k);
+			let dist = length(sample_pos - w_p);
+			// The constant part of the denominator diffuses the glow close to the light
+			// should add to uniform
+			let falloff = (light.intensity * 100.) / (40. + (dist * dist * light.falloff));
+			received += falloff * soft_shadow(w_p, sample_pos);
+		}
+
+		// Multiply in the base color to make sure we actually "light up" a tile instead
+		// of just diffusing the color
+		final_color += (base * light.color) * (received / f32(samples)) * cone;
+	}
+
+	for (var i: i32 = 0; i < MAX_PARTICLE_LIGHTS; i = i + 1) {
+		let light = particle_lights[i];
+		if (light.intensity <= 0.0) {
+			continue;
+		}
+		let dist = length(light.position - w_p);
+		let falloff = (light.intensity * 100.) / (40. + (dist * dist * light.falloff));
+		final_color += (base * light.color.rgb) * falloff;
+	}
+
+	return final_color;
+}
@@ -1,19 +1,41 @@
+mod bitmap_font;
 mod camera;
 mod debug_node;
+mod deferred_lighting;
+mod instance_buffer;
+mod light_culling;
+mod lighting;
+mod nine_slice;
 mod output_node;
+mod packed_sprite;
+mod palette;
+mod particles;
 mod pipeline_utils;
 mod renderer;
 mod resources;
 mod sdf;
+mod sprite_grid;
 mod sprite_node;
 mod texture;
 mod texture_atlas;
+mod tile_animation;
+mod tilemap_node;
 mod utils;
 
+pub use bitmap_font::{Align, BitmapFont, Text};
 pub use camera::Camera;
 pub use debug_node::DebugNode;
-pub use output_node::OutputNode;
+pub use deferred_lighting::DeferredLightingNode;
+pub use lighting::{Light, LightShape};
+pub use nine_slice::NineSlice;
+pub use output_node::{OutputNode, Resolution, UpscaleFilter};
+pub use packed_sprite::PackedSpriteInstance;
+pub use palette::{Palette, PALETTE_SIZE};
+pub use particles::{Emitter, EmitterId, ParticleNode};
 pub use renderer::Renderer;
 pub use sdf::SDFPipeline;
-pub use sprite_node::{Light, SpriteInstance, SpriteNode};
+pub use sprite_grid::SpriteGrid;
+pub use sprite_node::{BlendMode, SpriteBatch, SpriteBuffer, SpriteInstance, SpriteNode};
 pub use texture::Texture;
+pub use tile_animation::TileAnimation;
+pub use tilemap_node::TilemapNode;
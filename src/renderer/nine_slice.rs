@@ -0,0 +1,123 @@
+use super::SpriteInstance;
+
+/// An atlas region split into a 3x3 grid by fixed borders, for panels and frames that
+/// can be drawn at any size.
+///
+/// Corners keep their pixel size, edges stretch along their length and the centre
+/// stretches both ways. Every piece is a regular [`SpriteInstance`], so panels go through
+/// the same pipeline, atlas and lighting as everything else.
+#[derive(Clone, Copy, Debug)]
+pub struct NineSlice {
+    texture_origin: [f32; 2],
+    size: [f32; 2],
+    // Border widths in atlas pixels: left, right, top, bottom
+    borders: [f32; 4],
+    // World units per atlas pixel for the borders
+    pixel_scale: f32,
+    layer: f32,
+    tint: [f32; 4],
+    lit: bool,
+}
+
+impl NineSlice {
+    /// `borders` are the left, right, top and bottom border widths in atlas pixels.
+    pub fn new(texture_origin: [f32; 2], size: [f32; 2], borders: [f32; 4]) -> Self {
+        Self {
+            texture_origin,
+            size,
+            borders,
+            pixel_scale: 1.,
+            layer: 0.,
+            tint: [1., 1., 1., 1.],
+            lit: true,
+        }
+    }
+
+    /// Size of an atlas pixel in world units, applied to the borders.
+    pub fn with_pixel_scale(mut self, pixel_scale: f32) -> Self {
+        self.pixel_scale = pixel_scale;
+        self
+    }
+
+    pub fn with_layer(mut self, layer: f32) -> Self {
+        self.layer = layer;
+        self
+    }
+
+    pub fn with_tint(mut self, tint: [f32; 4]) -> Self {
+        self.tint = tint;
+        self
+    }
+
+    /// Whether the scene lights affect the panel, see [`SpriteInstance::with_lighting`].
+    pub fn with_lighting(mut self, lit: bool) -> Self {
+        self.lit = lit;
+        self
+    }
+
+    /// The instances covering the rectangle with its bottom left corner at `position`.
+    ///
+    /// When the rectangle is smaller than the borders, the borders shrink to fit. Pieces
+    /// that end up empty, like the edges of a region without borders, are left out.
+    pub fn instances(&self, position: [f32; 2], size: [f32; 2]) -> Vec<SpriteInstance> {
+        let [left, right, top, bottom] = self.borders;
+        // atlas rows go top down, world rows bottom up
+        let columns = Self::split(self.size[0], left, right, size[0], self.pixel_scale);
+        let rows = Self::split(self.size[1], bottom, top, size[1], self.pixel_scale);
+
+        let mut instances = Vec::with_capacity(9);
+        let mut y = position[1];
+        for &(atlas_y, atlas_height, height) in rows.iter() {
+            let mut x = position[0];
+            for &(atlas_x, atlas_width, width) in columns.iter() {
+                if atlas_width > 0. && atlas_height > 0. && width > 0. && height > 0. {
+                    instances.push(
+                        SpriteInstance::new(
+                            [atlas_width, atlas_height],
+                            [
+                                self.texture_origin[0] + atlas_x,
+                                // flip the offset back into atlas orientation
+                                self.texture_origin[1] + self.size[1] - atlas_y - atlas_height,
+                            ],
+                            [x, y],
+                        )
+                        .with_scale([width / atlas_width, height / atlas_height])
+                        .with_layer(self.layer)
+                        .with_tint(self.tint)
+                        .with_lighting(self.lit),
+                    );
+                }
+                x += width;
+            }
+            y += height;
+        }
+
+        instances
+    }
+
+    // Split one axis into its three pieces, as (atlas offset, atlas length, world length).
+    // The offset is measured from the low end, which is the bottom for rows.
+    fn split(
+        atlas_length: f32,
+        low: f32,
+        high: f32,
+        length: f32,
+        pixel_scale: f32,
+    ) -> [(f32, f32, f32); 3] {
+        let borders = (low + high) * pixel_scale;
+        let fit = if borders > length && borders > 0. {
+            length / borders
+        } else {
+            1.
+        };
+        let low_length = low * pixel_scale * fit;
+        let high_length = high * pixel_scale * fit;
+        let middle = atlas_length - low - high;
+
+        [
+            (0., low, low_length),
+            (low, middle, length - low_length - high_length),
+            (atlas_length - high, high, high_length),
+        ]
+    }
+}
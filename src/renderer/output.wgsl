@@ -8,24 +8,63 @@ struct VertexOutput {
 	@location(0) tex_coords: vec2<f32>,
 }
 
+struct Output {
+	// Center and half size of the quad in clip space
+	quad: vec4<f32>,
+	source_size: vec2<f32>,
+	// Window pixels per texel
+	scale: f32,
+	sharp: u32,
+	// Includes the margin around the source
+	texture_size: vec2<f32>,
+	// Sub-pixel camera offset, in texels
+	offset: vec2<f32>,
+}
+
+@group(0) @binding(0)
+var texture: texture_2d<f32>;
+
+@group(0) @binding(1)
+var texture_sampler: sampler;
+
+@group(0) @binding(2)
+var<uniform> output: Output;
+
+@group(0) @binding(3)
+var overlay_texture: texture_2d<f32>;
+
 @vertex
 fn vs_main(input: VertexInput) -> VertexOutput {
 	var out: VertexOutput;
-	let position = vec4(input.position, 0.0, 1.0);
+	let position = vec4(input.position * output.quad.zw + output.quad.xy, 0.0, 1.0);
 	out.clip_position = position;
 	out.tex_coords = input.tex_coords;
     return out;
 }
 
-@group(0) @binding(0)
-var texture: texture_2d<f32>;
+// Texture coordinates to sample for a texel position, in texels from the texture corner
+fn upscale_uv(texel: vec2<f32>) -> vec2<f32> {
+	// Sample the center of the texel, the same as nearest filtering
+	let nearest = (floor(texel) + 0.5) / output.texture_size;
+	// Sharp bilinear: every texel is a flat square, blending only across the last window
+	// pixel before its edge
+	let region = 0.5 - 0.5 / max(output.scale, 1.0);
+	let center_dist = fract(texel) - 0.5;
+	let f = (center_dist - clamp(center_dist, vec2(-region), vec2(region))) * output.scale + 0.5;
+	let sharp = (floor(texel) + f) / output.texture_size;
 
-@group(0) @binding(1)
-var texture_sampler: sampler;
+	return select(nearest, sharp, output.sharp != 0u);
+}
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-	var sampled = textureSample(texture, texture_sampler, in.tex_coords);
+	let margin = (output.texture_size - output.source_size) / 2.0;
+	let texel = in.tex_coords * output.source_size + margin;
+	var sampled = textureSample(texture, texture_sampler, upscale_uv(texel + output.offset));
+	// The overlay is fixed to the screen, so it ignores the camera offset
+	let overlay = textureSample(overlay_texture, texture_sampler, upscale_uv(texel));
+	// Blending onto a transparent target leaves the overlay premultiplied
+	sampled = vec4(overlay.rgb + sampled.rgb * (1.0 - overlay.a), 1.0);
 	// sampled = sampled * vec4(0.10, 0.10, 0.10, 1.0);
     return sampled;
 }
@@ -1,41 +1,135 @@
 use wgpu::{include_wgsl, util::DeviceExt};
 
-use super::{
-    pipeline_utils::{
-        create_basic_sampler_bind_group, create_basic_sampler_bind_group_layout,
-        create_render_pipeline,
-    },
-    Texture,
-};
+use super::{camera::MARGIN, pipeline_utils::create_render_pipeline, Texture};
+
+/// Size of the texture the scene is drawn into before it is scaled to the window.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// Match the window, one texel per window pixel
+    Window,
+    /// Fixed size in pixels, upscaled to fit the window
+    Virtual { width: u32, height: u32 },
+}
+
+/// How the scene texture is scaled up to the window.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpscaleFilter {
+    /// Largest integer factor that fits, the rest of the window is letterboxed
+    Integer,
+    /// Fill the window keeping the aspect ratio, only blending the texel edges
+    SharpBilinear,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct OutputUniform {
+    // Center and half size of the quad in clip space
+    quad: [f32; 4],
+    source_size: [f32; 2],
+    // Window pixels per texel
+    scale: f32,
+    sharp: u32,
+    // Includes the margin around the source
+    texture_size: [f32; 2],
+    // Sub-pixel camera offset, in texels
+    offset: [f32; 2],
+}
 
 pub struct OutputNode {
     pipeline: wgpu::RenderPipeline,
     vertex_buffer: wgpu::Buffer,
     index_buffer: wgpu::Buffer,
-    sampler_bind_group: wgpu::BindGroup,
+    uniform_buffer: wgpu::Buffer,
+    // Bilinear, nearest sampling is done by snapping to texel centers in the shader
+    sampler: wgpu::Sampler,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    filter: UpscaleFilter,
+    source_size: [u32; 2],
+    window_size: [u32; 2],
+    offset: [f32; 2],
 }
 
 impl OutputNode {
     pub fn new(
         device: &wgpu::Device,
+        queue: &wgpu::Queue,
         config: &wgpu::SurfaceConfiguration,
-        sampler: &wgpu::Sampler,
         target: &Texture,
+        overlay: &Texture,
     ) -> Self {
-        let sampler_bind_group_layout =
-            create_basic_sampler_bind_group_layout(&device, Some("output bg layout"));
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("output bg layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
+            ],
+        });
 
-        let sampler_bind_group = create_basic_sampler_bind_group(
-            &device,
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("output sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("output uniform buffer"),
+            size: std::mem::size_of::<OutputUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let bind_group = Self::create_bind_group(
+            device,
+            &bind_group_layout,
             &sampler,
-            &sampler_bind_group_layout,
-            &target,
-            Some("output bg"),
+            &uniform_buffer,
+            target,
+            overlay,
         );
 
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
             label: Some("Sprite renderer pipeline layout"),
-            bind_group_layouts: &[&sampler_bind_group_layout],
+            bind_group_layouts: &[&bind_group_layout],
             push_constant_ranges: &[],
         });
 
@@ -58,19 +152,156 @@ impl OutputNode {
             &device,
             &pipeline_layout,
             config.format,
+            Some(wgpu::BlendState::ALPHA_BLENDING),
+            None,
             &[Vertex::desc()],
             wgpu::PrimitiveTopology::TriangleList,
             shader,
             Some("sprite renderer pipeline"),
         );
 
-        Self {
+        let output_node = Self {
             pipeline,
             vertex_buffer,
             index_buffer,
-            sampler_bind_group,
+            uniform_buffer,
+            sampler,
+            bind_group_layout,
+            bind_group,
+            filter: UpscaleFilter::Integer,
+            source_size: Self::source_size(target),
+            window_size: [config.width, config.height],
+            offset: [0., 0.],
+        };
+        output_node.write_uniform(queue);
+        output_node
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        sampler: &wgpu::Sampler,
+        uniform_buffer: &wgpu::Buffer,
+        target: &Texture,
+        overlay: &Texture,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("output bg"),
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&target.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&overlay.view),
+                },
+            ],
+        })
+    }
+
+    // The part of the target the camera sees, without the margin
+    fn source_size(target: &Texture) -> [u32; 2] {
+        [
+            target.size.width - 2 * MARGIN,
+            target.size.height - 2 * MARGIN,
+        ]
+    }
+
+    /// Point the node at new scene and overlay textures, after they have been recreated at
+    /// another size.
+    pub fn set_target(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        target: &Texture,
+        overlay: &Texture,
+    ) {
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.sampler,
+            &self.uniform_buffer,
+            target,
+            overlay,
+        );
+        self.source_size = Self::source_size(target);
+        self.write_uniform(queue);
+    }
+
+    /// Shift the scene by the camera's [`Camera::subpixel_offset`](super::Camera), so snapped
+    /// movement still scrolls smoothly on screen.
+    pub fn set_subpixel_offset(&mut self, queue: &wgpu::Queue, offset: [f32; 2]) {
+        if offset != self.offset {
+            self.offset = offset;
+            self.write_uniform(queue);
+        }
+    }
+
+    pub fn resize(&mut self, queue: &wgpu::Queue, width: u32, height: u32) {
+        self.window_size = [width, height];
+        self.write_uniform(queue);
+    }
+
+    pub fn set_filter(&mut self, queue: &wgpu::Queue, filter: UpscaleFilter) {
+        self.filter = filter;
+        self.write_uniform(queue);
+    }
+
+    pub fn filter(&self) -> UpscaleFilter {
+        self.filter
+    }
+
+    /// Window pixels per scene texel.
+    fn scale(&self) -> f32 {
+        let [source_width, source_height] = self.source_size;
+        let [width, height] = self.window_size;
+        let fit = (width as f32 / source_width as f32).min(height as f32 / source_height as f32);
+        match self.filter {
+            // A window smaller than the scene can't fit a whole factor, so it shrinks instead
+            UpscaleFilter::Integer if fit >= 1. => fit.floor(),
+            _ => fit,
         }
     }
+
+    fn write_uniform(&self, queue: &wgpu::Queue) {
+        let scale = self.scale();
+        let [width, height] = self.window_size.map(|size| size as f32);
+        let [source_width, source_height] = self.source_size.map(|size| size as f32);
+        // The quad starts on a whole pixel, otherwise texel edges land between pixels
+        let quad_width = source_width * scale;
+        let quad_height = source_height * scale;
+        let left = ((width - quad_width) / 2.).floor();
+        let top = ((height - quad_height) / 2.).floor();
+
+        let uniform = OutputUniform {
+            quad: [
+                (left + quad_width / 2.) / width * 2. - 1.,
+                1. - (top + quad_height / 2.) / height * 2.,
+                quad_width / width,
+                quad_height / height,
+            ],
+            source_size: [source_width, source_height],
+            scale,
+            sharp: (self.filter == UpscaleFilter::SharpBilinear) as u32,
+            texture_size: [
+                source_width + 2. * MARGIN as f32,
+                source_height + 2. * MARGIN as f32,
+            ],
+            // The texture's y axis points down, the world's up
+            offset: [self.offset[0], -self.offset[1]],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
 }
 
 #[repr(C)]
@@ -128,7 +359,7 @@ where
             output_renderer.index_buffer.slice(..),
             wgpu::IndexFormat::Uint16,
         );
-        self.set_bind_group(0, &output_renderer.sampler_bind_group, &[]);
+        self.set_bind_group(0, &output_renderer.bind_group, &[]);
         self.draw_indexed(0..INDICES.len() as u32, 0, 0..1)
     }
 }
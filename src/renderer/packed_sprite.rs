@@ -0,0 +1,136 @@
+use half::f16;
+
+use super::SpriteInstance;
+
+/// A [`SpriteInstance`] squeezed into 28 bytes instead of 72, for scenes with so many
+/// sprites that uploading them dominates the frame.
+///
+/// Precision is traded for size:
+/// - atlas coordinates are whole pixels up to 65535
+/// - translations are signed fixed point with half a pixel of precision, in
+///   `MIN_POSITION..=MAX_POSITION`
+/// - scale, rotation and layer are f16
+/// - tint is 8 bits per channel
+/// - pivot, palette row and flags share a single u32, with 8 bits each
+///
+/// Draw them from [`SpriteBuffer::Packed`](super::SpriteBuffer::Packed).
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct PackedSpriteInstance {
+    size: [u16; 2],
+    texture_origin: [u16; 2],
+    // Fixed point, divide by POSITION_SCALE for world units
+    translation: [i16; 2],
+    scale: [f16; 2],
+    rotation_layer: [f16; 2],
+    tint: [u8; 4],
+    // pivot x | pivot y << 8 | palette << 16 | flags << 24
+    bits: u32,
+}
+
+impl PackedSpriteInstance {
+    /// Translations are stored in units of 1 / POSITION_SCALE pixels.
+    pub const POSITION_SCALE: f32 = 2.;
+    /// Smallest translation that fits on either axis, smaller values are clamped.
+    pub const MIN_POSITION: f32 = i16::MIN as f32 / Self::POSITION_SCALE;
+    /// Largest translation that fits on either axis, larger values are clamped.
+    pub const MAX_POSITION: f32 = i16::MAX as f32 / Self::POSITION_SCALE;
+    /// Palette rows above this can't be packed, the row is clamped to it.
+    pub const MAX_PALETTE: u32 = 0xFE;
+    const NO_PALETTE: u32 = 0xFF;
+
+    const ATTRIBS: [wgpu::VertexAttribute; 7] = wgpu::vertex_attr_array![
+        2 => Uint16x2,
+        3 => Uint16x2,
+        4 => Sint16x2,
+        5 => Float16x2,
+        6 => Float16x2,
+        7 => Unorm8x4,
+        8 => Uint32
+    ];
+    pub(super) fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<PackedSpriteInstance>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+
+    pub fn translation(&self) -> [f32; 2] {
+        self.translation.map(|t| t as f32 / Self::POSITION_SCALE)
+    }
+
+    pub fn set_translation(&mut self, translation: [f32; 2]) {
+        self.translation = translation.map(|t| {
+            (t.clamp(Self::MIN_POSITION, Self::MAX_POSITION) * Self::POSITION_SCALE).round() as i16
+        });
+    }
+}
+
+impl From<&SpriteInstance> for PackedSpriteInstance {
+    fn from(instance: &SpriteInstance) -> Self {
+        let pixels = |v: f32| v.round().clamp(0., u16::MAX as f32) as u16;
+        let unorm = |v: f32| (v.clamp(0., 1.) * 255.).round() as u32;
+        let palette = match instance.palette {
+            SpriteInstance::NO_PALETTE => Self::NO_PALETTE,
+            row => row.min(Self::MAX_PALETTE),
+        };
+
+        let mut packed = Self {
+            size: [pixels(instance.size.x()), pixels(instance.size.y())],
+            texture_origin: [
+                pixels(instance.texture_origin.x()),
+                pixels(instance.texture_origin.y()),
+            ],
+            translation: [0; 2],
+            scale: [
+                f16::from_f32(instance.scale.x()),
+                f16::from_f32(instance.scale.y()),
+            ],
+            rotation_layer: [
+                f16::from_f32(instance.rotation),
+                f16::from_f32(instance.layer),
+            ],
+            tint: instance.tint.map(|c| unorm(c) as u8),
+            bits: unorm(instance.pivot.x())
+                | unorm(instance.pivot.y()) << 8
+                | palette << 16
+                | (instance.flags & 0xFF & !SpriteInstance::ANIMATED) << 24,
+        };
+        packed.set_translation([instance.translation.x(), instance.translation.y()]);
+
+        packed
+    }
+}
+
+impl From<SpriteInstance> for PackedSpriteInstance {
+    fn from(instance: SpriteInstance) -> Self {
+        Self::from(&instance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negative_translation_survives_packing() {
+        let instance = SpriteInstance::new([16., 16.], [0., 0.], [-120.5, -3.]);
+        let packed = PackedSpriteInstance::from(&instance);
+        assert_eq!(packed.translation(), [-120.5, -3.]);
+    }
+
+    #[test]
+    fn translation_is_clamped_to_range() {
+        let mut packed =
+            PackedSpriteInstance::from(SpriteInstance::new([16., 16.], [0., 0.], [0., 0.]));
+        packed.set_translation([-1e6, 1e6]);
+        assert_eq!(
+            packed.translation(),
+            [
+                PackedSpriteInstance::MIN_POSITION,
+                PackedSpriteInstance::MAX_POSITION
+            ]
+        );
+    }
+}
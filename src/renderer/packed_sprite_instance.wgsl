@@ -0,0 +1,34 @@
+
+// See PackedSpriteInstance for the layout
+struct PackedInstanceInput {
+	@location(2) size: vec2<u32>,
+	@location(3) texture_origin: vec2<u32>,
+	@location(4) translation: vec2<i32>,
+	@location(5) scale: vec2<f32>,
+	@location(6) rotation_layer: vec2<f32>,
+	@location(7) tint: vec4<f32>,
+	@location(8) bits: u32,
+}
+
+// Must match PackedSpriteInstance::POSITION_SCALE
+const POSITION_SCALE: f32 = 2.0;
+const PACKED_NO_PALETTE: u32 = 0xFFu;
+
+@vertex
+fn vs_main(input: VertexInput, packed: PackedInstanceInput) -> VertexOutput {
+	var ins: InstanceInput;
+	ins.size = vec2<f32>(packed.size);
+	ins.texture_origin = vec2<f32>(packed.texture_origin);
+	ins.translation = vec2<f32>(packed.translation) / POSITION_SCALE;
+	ins.scale = packed.scale;
+	ins.pivot = vec2(f32(packed.bits & 0xFFu), f32((packed.bits >> 8u) & 0xFFu)) / 255.0;
+	ins.rotation = packed.rotation_layer.x;
+	ins.layer = packed.rotation_layer.y;
+	ins.tint = packed.tint;
+	ins.palette = (packed.bits >> 16u) & 0xFFu;
+	if (ins.palette == PACKED_NO_PALETTE) {
+		ins.palette = NO_PALETTE;
+	}
+	ins.flags = packed.bits >> 24u;
+	return sprite_vertex(input, ins);
+}
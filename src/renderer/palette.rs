@@ -0,0 +1,53 @@
+use super::Texture;
+
+/// Number of colours in a single palette row.
+pub const PALETTE_SIZE: usize = 16;
+
+/// A palette texture where every row is a colour scheme.
+///
+/// Indexed sprites store a palette index in the red channel of the atlas, and the
+/// `palette` field of a `SpriteInstance` picks the row to look the colour up in.
+pub struct Palette {
+    pub texture: Texture,
+}
+
+impl Palette {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, rows: &[[u32; PALETTE_SIZE]]) -> Self {
+        // Always keep at least one row around so the texture is valid
+        let height = rows.len().max(1) as u32;
+        let texture = Texture::create_2d_texture(
+            device,
+            PALETTE_SIZE as u32,
+            height,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            Some("palette texture"),
+        );
+
+        let data: Vec<u8> = rows
+            .iter()
+            .flatten()
+            .flat_map(|c| [(c >> 16) as u8, (c >> 8) as u8, *c as u8, 0xFF])
+            .collect();
+
+        if !data.is_empty() {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                &data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * PALETTE_SIZE as u32),
+                    rows_per_image: Some(height),
+                },
+                texture.size,
+            );
+        }
+
+        Self { texture }
+    }
+}
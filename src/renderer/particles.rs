@@ -0,0 +1,658 @@
+use std::ops::Range;
+
+use anyhow::bail;
+use wgpu::util::DeviceExt;
+
+use super::{lighting::Lighting, SpriteInstance};
+
+/// Particles shared by every emitter. Each emitter reserves a slice of them up front.
+pub const MAX_PARTICLES: usize = 65536;
+const MAX_EMITTERS: usize = 64;
+const WORKGROUP_SIZE: u32 = 64;
+
+/// Describes how an emitter spawns and moves its particles.
+///
+/// Particles are simulated on the GPU and drawn as sprites from
+/// [`SpriteBuffer::Particles`](super::SpriteBuffer::Particles), animating through the atlas
+/// frames and blending between the start and end colours over their lifetime.
+#[derive(Clone, Copy, Debug)]
+pub struct Emitter {
+    pub position: [f32; 2],
+    // Particles per second
+    pub rate: f32,
+    // Seconds, picked at random between the two
+    lifetime: [f32; 2],
+    // World units per second, each component picked at random between the two
+    velocity: [[f32; 2]; 2],
+    gravity: [f32; 2],
+    colors: [[f32; 4]; 2],
+    // Frames are laid out left to right in the atlas
+    frame_origin: [f32; 2],
+    frame_size: [f32; 2],
+    frame_count: u32,
+    scale: f32,
+    layer: f32,
+    lit: bool,
+    light: Option<ParticleLights>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ParticleLights {
+    intensity: f32,
+    falloff: f32,
+    count: u32,
+}
+
+impl Emitter {
+    pub fn new(
+        position: [f32; 2],
+        rate: f32,
+        frame_origin: [f32; 2],
+        frame_size: [f32; 2],
+    ) -> Self {
+        Self {
+            position,
+            rate,
+            lifetime: [1., 1.],
+            velocity: [[0., 0.], [0., 0.]],
+            gravity: [0., 0.],
+            colors: [[1., 1., 1., 1.], [1., 1., 1., 1.]],
+            frame_origin,
+            frame_size,
+            frame_count: 1,
+            scale: 1.,
+            layer: 0.,
+            lit: true,
+            light: None,
+        }
+    }
+
+    /// Play `count` frames, each `frame_size` to the right of the last, over a lifetime.
+    pub fn with_frames(mut self, count: u32) -> Self {
+        self.frame_count = count.max(1);
+        self
+    }
+
+    pub fn with_lifetime(mut self, min: f32, max: f32) -> Self {
+        self.lifetime = [min, max];
+        self
+    }
+
+    pub fn with_velocity(mut self, min: [f32; 2], max: [f32; 2]) -> Self {
+        self.velocity = [min, max];
+        self
+    }
+
+    /// Acceleration in world units per second squared.
+    pub fn with_gravity(mut self, gravity: [f32; 2]) -> Self {
+        self.gravity = gravity;
+        self
+    }
+
+    /// Tint at the start and at the end of a particle's life.
+    pub fn with_colors(mut self, start: [f32; 4], end: [f32; 4]) -> Self {
+        self.colors = [start, end];
+        self
+    }
+
+    pub fn with_scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn with_layer(mut self, layer: f32) -> Self {
+        self.layer = layer;
+        self
+    }
+
+    /// Whether the scene lights affect the particles, see [`SpriteInstance::with_lighting`].
+    pub fn with_lighting(mut self, lit: bool) -> Self {
+        self.lit = lit;
+        self
+    }
+
+    /// Let up to `count` of the particles glow in their current colour. Particle lights are
+    /// shared by all emitters, see [`Lighting::MAX_PARTICLE_LIGHTS`].
+    pub fn with_lights(mut self, intensity: f32, falloff: f32, count: u32) -> Self {
+        self.light = Some(ParticleLights {
+            intensity,
+            falloff,
+            count,
+        });
+        self
+    }
+
+    // Enough particles that the ring never overwrites a live one at the configured rate
+    fn capacity(&self) -> u32 {
+        ((self.rate * self.lifetime[0].max(self.lifetime[1])).ceil() as u32 + 1).max(1)
+    }
+}
+
+/// Refers to an emitter added to the [`ParticleNode`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EmitterId {
+    index: usize,
+    generation: u32,
+}
+
+struct EmitterState {
+    emitter: Emitter,
+    particles: Range<u32>,
+    lights: Range<u32>,
+    // Next particle in the ring to respawn
+    cursor: u32,
+    // Fraction of a particle left over from the previous frame
+    carry: f32,
+}
+
+#[derive(Default)]
+struct EmitterSlot {
+    generation: u32,
+    state: Option<EmitterState>,
+}
+
+/// Simulates particles in a compute pass and writes them out as sprite instances, plus the
+/// particle lights read by [`Lighting`].
+pub struct ParticleNode {
+    pipeline: wgpu::ComputePipeline,
+    particles_buffer: wgpu::Buffer,
+    emitters_buffer: wgpu::Buffer,
+    params_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    params_bind_group: wgpu::BindGroup,
+    slots: Vec<EmitterSlot>,
+    time: f32,
+}
+
+impl ParticleNode {
+    const SHADER: &'static str = include_str!("particles.wgsl");
+
+    /// `instance_buffer` receives a sprite instance per particle and needs room for
+    /// [`MAX_PARTICLES`].
+    pub fn new(device: &wgpu::Device, instance_buffer: &wgpu::Buffer, lighting: &Lighting) -> Self {
+        let particles_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Particles Buffer"),
+            size: (MAX_PARTICLES * std::mem::size_of::<Particle>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let emitters_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Emitters Buffer"),
+            size: (MAX_EMITTERS * std::mem::size_of::<GpuEmitter>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Particle Params Buffer"),
+            contents: bytemuck::cast_slice(&[Params::default()]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let storage_entry = |binding, read_only| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("particles bind group layout"),
+            entries: &[
+                storage_entry(0, false),
+                storage_entry(1, true),
+                storage_entry(2, false),
+                storage_entry(3, false),
+            ],
+        });
+        let params_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("particle params bind group layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("particles bind group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: particles_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: emitters_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: instance_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: lighting.particle_lights_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        let params_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("particle params bind group"),
+            layout: &params_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: params_buffer.as_entire_binding(),
+            }],
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("particles shader module"),
+            source: wgpu::ShaderSource::Wgsl(Self::SHADER.into()),
+        });
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("particles pipeline layout"),
+            bind_group_layouts: &[&bind_group_layout, &params_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("particles compute pipeline"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: "main",
+        });
+
+        Self {
+            pipeline,
+            particles_buffer,
+            emitters_buffer,
+            params_buffer,
+            bind_group,
+            params_bind_group,
+            slots: Vec::new(),
+            time: 0.,
+        }
+    }
+
+    /// Start emitting. Fails when there is no room left for the emitter's particles.
+    /// Particle lights are handed out while they last, later emitters get fewer or none.
+    pub fn add_emitter(
+        &mut self,
+        emitter: Emitter,
+        queue: &wgpu::Queue,
+    ) -> anyhow::Result<EmitterId> {
+        let index = match self.slots.iter().position(|slot| slot.state.is_none()) {
+            Some(index) => index,
+            None if self.slots.len() < MAX_EMITTERS => {
+                self.slots.push(EmitterSlot::default());
+                self.slots.len() - 1
+            }
+            None => bail!("particles: more than {} emitters", MAX_EMITTERS),
+        };
+
+        let capacity = emitter.capacity();
+        let Some(particles) = allocate(
+            self.emitters().map(|state| state.particles.clone()),
+            capacity,
+            MAX_PARTICLES as u32,
+        ) else {
+            bail!(
+                "particles: no room for {} more particles out of {}",
+                capacity,
+                MAX_PARTICLES
+            );
+        };
+
+        // take whatever particle lights are left in one piece
+        let wanted = emitter.light.map_or(0, |light| light.count.min(capacity));
+        let lights = (1..=wanted)
+            .rev()
+            .find_map(|count| {
+                allocate(
+                    self.emitters().map(|state| state.lights.clone()),
+                    count,
+                    Lighting::MAX_PARTICLE_LIGHTS as u32,
+                )
+            })
+            .unwrap_or(0..0);
+
+        // Whatever the last emitter in this range left behind is dead
+        queue.write_buffer(
+            &self.particles_buffer,
+            (particles.start as usize * std::mem::size_of::<Particle>()) as u64,
+            bytemuck::cast_slice(&vec![Particle::default(); particles.len()]),
+        );
+
+        let slot = &mut self.slots[index];
+        slot.state = Some(EmitterState {
+            emitter,
+            particles,
+            lights,
+            cursor: 0,
+            carry: 0.,
+        });
+
+        Ok(EmitterId {
+            index,
+            generation: slot.generation,
+        })
+    }
+
+    /// Stop emitting and clear the emitter's particles and lights right away.
+    pub fn remove_emitter(
+        &mut self,
+        id: EmitterId,
+        instance_buffer: &wgpu::Buffer,
+        lighting: &Lighting,
+        queue: &wgpu::Queue,
+    ) -> bool {
+        let Some(slot) = self
+            .slots
+            .get_mut(id.index)
+            .filter(|slot| slot.generation == id.generation)
+        else {
+            return false;
+        };
+        let Some(state) = slot.state.take() else {
+            return false;
+        };
+        slot.generation = slot.generation.wrapping_add(1);
+
+        let instance_size = std::mem::size_of::<SpriteInstance>();
+        queue.write_buffer(
+            instance_buffer,
+            (state.particles.start as usize * instance_size) as u64,
+            &vec![0; state.particles.len() * instance_size],
+        );
+        let light_size = std::mem::size_of::<GpuParticleLight>();
+        queue.write_buffer(
+            &lighting.particle_lights_buffer,
+            (state.lights.start as usize * light_size) as u64,
+            &vec![0; state.lights.len() * light_size],
+        );
+
+        true
+    }
+
+    pub fn emitter_mut(&mut self, id: EmitterId) -> Option<&mut Emitter> {
+        self.slots
+            .get_mut(id.index)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.state.as_mut())
+            .map(|state| &mut state.emitter)
+    }
+
+    /// Instances to draw from the particle instance buffer. Gaps between emitters hold
+    /// dead particles, which are empty quads.
+    pub fn instance_count(&self) -> u32 {
+        self.emitters()
+            .map(|state| state.particles.end)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Spawn new particles and move the rest `delta_time` seconds forward.
+    pub fn update(&mut self, delta_time: f32, device: &wgpu::Device, queue: &wgpu::Queue) {
+        self.time += delta_time;
+
+        let mut emitters = Vec::new();
+        for state in self.slots.iter_mut().filter_map(|slot| slot.state.as_mut()) {
+            let capacity = state.particles.len() as u32;
+            let spawn = state.emitter.rate.max(0.) * delta_time + state.carry;
+            let spawn_count = (spawn.floor() as u32).min(capacity);
+            state.carry = spawn.fract();
+
+            emitters.push(GpuEmitter::new(state, spawn_count));
+            state.cursor = (state.cursor + spawn_count) % capacity;
+        }
+        if emitters.is_empty() {
+            return;
+        }
+
+        queue.write_buffer(&self.emitters_buffer, 0, bytemuck::cast_slice(&emitters));
+        queue.write_buffer(
+            &self.params_buffer,
+            0,
+            bytemuck::cast_slice(&[Params {
+                delta_time,
+                time: self.time,
+                emitter_count: emitters.len() as u32,
+                _padding: 0,
+            }]),
+        );
+
+        let largest = emitters.iter().map(|e| e.capacity).max().unwrap_or(0);
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("particles"),
+        });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &self.bind_group, &[]);
+            pass.set_bind_group(1, &self.params_bind_group, &[]);
+            pass.dispatch_workgroups(largest.div_ceil(WORKGROUP_SIZE), emitters.len() as u32, 1);
+        }
+        queue.submit(Some(encoder.finish()));
+    }
+
+    fn emitters(&self) -> impl Iterator<Item = &EmitterState> {
+        self.slots.iter().filter_map(|slot| slot.state.as_ref())
+    }
+}
+
+// First fit of `size` slots in 0..limit around the ranges already in use
+fn allocate(used: impl Iterator<Item = Range<u32>>, size: u32, limit: u32) -> Option<Range<u32>> {
+    let mut used: Vec<_> = used.collect();
+    used.sort_by_key(|range| range.start);
+
+    let mut start = 0;
+    for range in used {
+        if range.start - start >= size {
+            break;
+        }
+        start = start.max(range.end);
+    }
+    (start + size <= limit).then_some(start..start + size)
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct Particle {
+    position: [f32; 2],
+    velocity: [f32; 2],
+    age: f32,
+    lifetime: f32,
+    seed: f32,
+    _padding: f32,
+}
+
+// Matches ParticleLight in lighting.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct GpuParticleLight {
+    position: [f32; 2],
+    intensity: f32,
+    falloff: f32,
+    color: [f32; 4],
+}
+
+// Matches Emitter in particles.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct GpuEmitter {
+    position: [f32; 2],
+    gravity: [f32; 2],
+    velocity_min: [f32; 2],
+    velocity_max: [f32; 2],
+    lifetime: [f32; 2],
+    frame_origin: [f32; 2],
+    frame_size: [f32; 2],
+    scale: f32,
+    layer: f32,
+    color_start: [f32; 4],
+    color_end: [f32; 4],
+    offset: u32,
+    capacity: u32,
+    spawn_start: u32,
+    spawn_count: u32,
+    frame_count: u32,
+    flags: u32,
+    light_offset: u32,
+    light_count: u32,
+    light_intensity: f32,
+    light_falloff: f32,
+    _padding: [f32; 2],
+}
+
+impl GpuEmitter {
+    fn new(state: &EmitterState, spawn_count: u32) -> Self {
+        let emitter = &state.emitter;
+        let light = emitter.light.unwrap_or(ParticleLights {
+            intensity: 0.,
+            falloff: 0.,
+            count: 0,
+        });
+        Self {
+            position: emitter.position,
+            gravity: emitter.gravity,
+            velocity_min: emitter.velocity[0],
+            velocity_max: emitter.velocity[1],
+            lifetime: emitter.lifetime,
+            frame_origin: emitter.frame_origin,
+            frame_size: emitter.frame_size,
+            scale: emitter.scale,
+            layer: emitter.layer,
+            color_start: emitter.colors[0],
+            color_end: emitter.colors[1],
+            offset: state.particles.start,
+            capacity: state.particles.len() as u32,
+            spawn_start: state.cursor,
+            spawn_count,
+            frame_count: emitter.frame_count,
+            flags: if emitter.lit {
+                0
+            } else {
+                SpriteInstance::UNLIT
+            },
+            light_offset: state.lights.start,
+            light_count: state.lights.len() as u32,
+            light_intensity: light.intensity,
+            light_falloff: light.falloff,
+            _padding: [0.; 2],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct Params {
+    delta_time: f32,
+    time: f32,
+    emitter_count: u32,
+    _padding: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::{offset_of, size_of};
+
+    use super::*;
+    use crate::renderer::lighting::tests::wgsl_layout;
+
+    #[test]
+    fn emitter_matches_wgsl() {
+        let (size, members) = wgsl_layout(ParticleNode::SHADER, "Emitter");
+        assert_eq!(size, size_of::<GpuEmitter>());
+        assert_eq!(
+            members,
+            [
+                ("position".into(), offset_of!(GpuEmitter, position)),
+                ("gravity".into(), offset_of!(GpuEmitter, gravity)),
+                ("velocity_min".into(), offset_of!(GpuEmitter, velocity_min)),
+                ("velocity_max".into(), offset_of!(GpuEmitter, velocity_max)),
+                ("lifetime".into(), offset_of!(GpuEmitter, lifetime)),
+                ("frame_origin".into(), offset_of!(GpuEmitter, frame_origin)),
+                ("frame_size".into(), offset_of!(GpuEmitter, frame_size)),
+                ("scale".into(), offset_of!(GpuEmitter, scale)),
+                ("layer".into(), offset_of!(GpuEmitter, layer)),
+                ("color_start".into(), offset_of!(GpuEmitter, color_start)),
+                ("color_end".into(), offset_of!(GpuEmitter, color_end)),
+                ("offset".into(), offset_of!(GpuEmitter, offset)),
+                ("capacity".into(), offset_of!(GpuEmitter, capacity)),
+                ("spawn_start".into(), offset_of!(GpuEmitter, spawn_start)),
+                ("spawn_count".into(), offset_of!(GpuEmitter, spawn_count)),
+                ("frame_count".into(), offset_of!(GpuEmitter, frame_count)),
+                ("flags".into(), offset_of!(GpuEmitter, flags)),
+                ("light_offset".into(), offset_of!(GpuEmitter, light_offset)),
+                ("light_count".into(), offset_of!(GpuEmitter, light_count)),
+                (
+                    "light_intensity".into(),
+                    offset_of!(GpuEmitter, light_intensity)
+                ),
+                (
+                    "light_falloff".into(),
+                    offset_of!(GpuEmitter, light_falloff)
+                ),
+                ("_padding".into(), offset_of!(GpuEmitter, _padding)),
+            ]
+        );
+    }
+
+    #[test]
+    fn sprite_instance_matches_wgsl() {
+        let (size, members) = wgsl_layout(ParticleNode::SHADER, "SpriteInstance");
+        assert_eq!(size, size_of::<SpriteInstance>());
+        assert_eq!(
+            members,
+            [
+                ("size".into(), offset_of!(SpriteInstance, size)),
+                (
+                    "texture_origin".into(),
+                    offset_of!(SpriteInstance, texture_origin)
+                ),
+                (
+                    "translation".into(),
+                    offset_of!(SpriteInstance, translation)
+                ),
+                ("scale".into(), offset_of!(SpriteInstance, scale)),
+                ("pivot".into(), offset_of!(SpriteInstance, pivot)),
+                ("rotation".into(), offset_of!(SpriteInstance, rotation)),
+                ("layer".into(), offset_of!(SpriteInstance, layer)),
+                ("tint".into(), offset_of!(SpriteInstance, tint)),
+                ("palette".into(), offset_of!(SpriteInstance, palette)),
+                ("flags".into(), offset_of!(SpriteInstance, flags)),
+            ]
+        );
+    }
+
+    #[test]
+    fn allocate_fits_first_gap() {
+        let used = [0..4, 8..12];
+        assert_eq!(allocate(used.iter().cloned(), 4, 16), Some(4..8));
+        assert_eq!(allocate(used.iter().cloned(), 5, 32), Some(12..17));
+    }
+
+    #[test]
+    fn allocate_respects_limit() {
+        assert_eq!(allocate(std::iter::once(0..10), 1, 10), None);
+        assert_eq!(allocate(std::iter::once(0..4), 7, 10), None);
+        assert_eq!(allocate(std::iter::once(0..4), 6, 10), Some(4..10));
+    }
+
+    #[test]
+    fn allocate_reuses_freed_ranges() {
+        let mut used = vec![0..4, 4..8, 8..12];
+        assert_eq!(allocate(used.iter().cloned(), 4, 12), None);
+        used.remove(1);
+        assert_eq!(allocate(used.iter().cloned(), 4, 12), Some(4..8));
+        used.clear();
+        assert_eq!(allocate(used.iter().cloned(), 12, 12), Some(0..12));
+    }
+}
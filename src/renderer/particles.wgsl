@@ -0,0 +1,168 @@
+// Simulates every particle and writes it out as a sprite instance. One invocation per
+// particle slot, global_id.y picks the emitter.
+
+struct Particle {
+	position: vec2<f32>,
+	velocity: vec2<f32>,
+	age: f32,
+	lifetime: f32,
+	seed: f32,
+	_padding: f32,
+}
+
+// See GpuEmitter
+struct Emitter {
+	position: vec2<f32>,
+	gravity: vec2<f32>,
+	velocity_min: vec2<f32>,
+	velocity_max: vec2<f32>,
+	lifetime: vec2<f32>,
+	frame_origin: vec2<f32>,
+	frame_size: vec2<f32>,
+	scale: f32,
+	layer: f32,
+	color_start: vec4<f32>,
+	color_end: vec4<f32>,
+	offset: u32,
+	capacity: u32,
+	spawn_start: u32,
+	spawn_count: u32,
+	frame_count: u32,
+	flags: u32,
+	light_offset: u32,
+	light_count: u32,
+	light_intensity: f32,
+	light_falloff: f32,
+	_padding: vec2<f32>,
+}
+
+// Laid out like SpriteInstance. The tint is split up so the struct keeps its 72 bytes
+// instead of being padded out to the alignment of a vec4.
+struct SpriteInstance {
+	size: vec2<f32>,
+	texture_origin: vec2<f32>,
+	translation: vec2<f32>,
+	scale: vec2<f32>,
+	pivot: vec2<f32>,
+	rotation: f32,
+	layer: f32,
+	tint: array<f32, 4>,
+	palette: u32,
+	flags: u32,
+}
+
+// See ParticleLight in lighting.wgsl
+struct GpuParticleLight {
+	position: vec2<f32>,
+	intensity: f32,
+	falloff: f32,
+	color: vec4<f32>,
+}
+
+struct Params {
+	delta_time: f32,
+	time: f32,
+	emitter_count: u32,
+	_padding: u32,
+}
+
+const NO_PALETTE: u32 = 0xFFFFFFFFu;
+// Emitter flag, matches SpriteInstance::UNLIT
+const UNLIT: u32 = 4u;
+
+@group(0) @binding(0)
+var<storage, read_write> particles: array<Particle>;
+
+@group(0) @binding(1)
+var<storage, read> emitters: array<Emitter>;
+
+@group(0) @binding(2)
+var<storage, read_write> instances: array<SpriteInstance>;
+
+@group(0) @binding(3)
+var<storage, read_write> particle_lights: array<GpuParticleLight>;
+
+@group(1) @binding(0)
+var<uniform> params: Params;
+
+// PCG hash, good enough to scatter particles
+fn hash(value: u32) -> u32 {
+	let state = value * 747796405u + 2891336453u;
+	let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+	return (word >> 22u) ^ word;
+}
+
+fn random(seed: ptr<function, u32>) -> f32 {
+	*seed = hash(*seed);
+	return f32(*seed) / 4294967295.0;
+}
+
+fn dead_instance() -> SpriteInstance {
+	var instance: SpriteInstance;
+	// a zero sized quad never reaches the fragment shader
+	instance.palette = NO_PALETTE;
+	return instance;
+}
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) global_id: vec3<u32>) {
+	if (global_id.y >= params.emitter_count) {
+		return;
+	}
+	let emitter = emitters[global_id.y];
+	let slot = global_id.x;
+	if (slot >= emitter.capacity) {
+		return;
+	}
+	let index = emitter.offset + slot;
+	var particle = particles[index];
+
+	// slots in [spawn_start, spawn_start + spawn_count) of the ring are respawned
+	if ((slot + emitter.capacity - emitter.spawn_start) % emitter.capacity < emitter.spawn_count) {
+		var seed = hash(index ^ bitcast<u32>(params.time));
+		particle.position = emitter.position;
+		particle.velocity = mix(
+			emitter.velocity_min,
+			emitter.velocity_max,
+			vec2(random(&seed), random(&seed))
+		);
+		particle.age = 0.0;
+		particle.lifetime = mix(emitter.lifetime.x, emitter.lifetime.y, random(&seed));
+		particle.seed = random(&seed);
+	} else {
+		particle.velocity += emitter.gravity * params.delta_time;
+		particle.position += particle.velocity * params.delta_time;
+		particle.age += params.delta_time;
+	}
+	particles[index] = particle;
+
+	let alive = particle.age < particle.lifetime;
+	let life = clamp(particle.age / max(particle.lifetime, 0.0001), 0.0, 1.0);
+	let color = mix(emitter.color_start, emitter.color_end, life);
+
+	if (alive) {
+		var instance: SpriteInstance;
+		let frame = min(u32(life * f32(emitter.frame_count)), emitter.frame_count - 1u);
+		instance.size = emitter.frame_size;
+		instance.texture_origin = emitter.frame_origin + vec2(f32(frame) * emitter.frame_size.x, 0.0);
+		instance.translation = particle.position;
+		instance.scale = vec2(emitter.scale);
+		instance.pivot = vec2(0.5);
+		instance.layer = emitter.layer;
+		instance.tint = array<f32, 4>(color.r, color.g, color.b, color.a);
+		instance.palette = NO_PALETTE;
+		instance.flags = emitter.flags & UNLIT;
+		instances[index] = instance;
+	} else {
+		instances[index] = dead_instance();
+	}
+
+	if (slot < emitter.light_count) {
+		var light: GpuParticleLight;
+		light.position = particle.position;
+		light.intensity = select(0.0, emitter.light_intensity * color.a, alive);
+		light.falloff = emitter.light_falloff;
+		light.color = color;
+		particle_lights[emitter.light_offset + slot] = light;
+	}
+}
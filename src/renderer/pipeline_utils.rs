@@ -50,11 +50,44 @@ pub fn create_basic_sampler_bind_group(
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn create_render_pipeline(
     device: &wgpu::Device,
     layout: &wgpu::PipelineLayout,
     color_format: wgpu::TextureFormat,
-    // depth_format: Option<wgpu::TextureFormat>,
+    blend: Option<wgpu::BlendState>,
+    depth_stencil: Option<wgpu::DepthStencilState>,
+    vertex_layouts: &[wgpu::VertexBufferLayout],
+    topology: wgpu::PrimitiveTopology,
+    shader: wgpu::ShaderModuleDescriptor,
+    label: Option<&str>,
+) -> wgpu::RenderPipeline {
+    create_multi_target_pipeline(
+        device,
+        layout,
+        &[Some(wgpu::ColorTargetState {
+            format: color_format,
+            blend,
+            write_mask: wgpu::ColorWrites::ALL,
+        })],
+        "fs_main",
+        depth_stencil,
+        vertex_layouts,
+        topology,
+        shader,
+        label,
+    )
+}
+
+/// Like [`create_render_pipeline`], for pipelines that write several color targets or use
+/// another fragment entry point.
+#[allow(clippy::too_many_arguments)]
+pub fn create_multi_target_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    targets: &[Option<wgpu::ColorTargetState>],
+    fragment_entry_point: &str,
+    depth_stencil: Option<wgpu::DepthStencilState>,
     vertex_layouts: &[wgpu::VertexBufferLayout],
     topology: wgpu::PrimitiveTopology,
     shader: wgpu::ShaderModuleDescriptor,
@@ -73,16 +106,8 @@ pub fn create_render_pipeline(
         },
         fragment: Some(wgpu::FragmentState {
             module: &shader,
-            entry_point: "fs_main",
-            targets: &[Some(wgpu::ColorTargetState {
-                format: color_format,
-                blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                // {
-                //     alpha: wgpu::BlendComponent::REPLACE,
-                //     color: wgpu::BlendComponent::REPLACE,
-                // }),
-                write_mask: wgpu::ColorWrites::ALL,
-            })],
+            entry_point: fragment_entry_point,
+            targets,
         }),
         primitive: wgpu::PrimitiveState {
             topology,
@@ -97,14 +122,7 @@ pub fn create_render_pipeline(
             // Requires Features::CONSERVATIVE_RASTERIZATION
             conservative: false,
         },
-        depth_stencil: None,
-        // depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
-        //     format,
-        //     depth_write_enabled: true,
-        //     depth_compare: wgpu::CompareFunction::Less,
-        //     stencil: wgpu::StencilState::default(),
-        //     bias: wgpu::DepthBiasState::default(),
-        // }),
+        depth_stencil,
         multisample: wgpu::MultisampleState {
             count: 1,
             mask: !0,
@@ -1,10 +1,15 @@
+use std::ops::Range;
+
 use bytemuck::NoUninit;
 use winit::window::Window;
 
 use super::{
-    camera::Camera, debug_node::DebugTexture, output_node::DrawToScreen, sprite_node::DrawSprite,
-    utils::to_linear_rgb, DebugNode, Light, OutputNode, SDFPipeline, SpriteInstance, SpriteNode,
-    Texture,
+    camera::Camera, debug_node::DebugTexture, deferred_lighting::DrawLighting, lighting::Lighting,
+    output_node::DrawToScreen, sprite_node::DrawSprite, texture_atlas::TextureAtlas,
+    tile_animation::TileAnimations, tilemap_node::DrawTilemap, utils::to_linear_rgb, DebugNode,
+    DeferredLightingNode, Emitter, EmitterId, Light, OutputNode, PackedSpriteInstance, Palette,
+    ParticleNode, Resolution, SDFPipeline, SpriteBatch, SpriteBuffer, SpriteInstance, SpriteNode,
+    Texture, TileAnimation, TilemapNode, UpscaleFilter, PALETTE_SIZE,
 };
 
 pub struct Renderer {
@@ -13,9 +18,18 @@ pub struct Renderer {
     pub queue: wgpu::Queue,
     pub config: wgpu::SurfaceConfiguration,
     pub sampler: wgpu::Sampler,
+    texture_atlas: TextureAtlas,
+    lighting: Lighting,
+    tile_animations: TileAnimations,
     sprite_node: SpriteNode,
+    deferred_lighting: DeferredLightingNode,
+    tilemap_node: Option<TilemapNode>,
+    particle_node: ParticleNode,
+    // Maps render target pixels to the screen, for the overlay sprites
+    overlay_camera: Camera,
     sdf_node: SDFPipeline,
     output_node: OutputNode,
+    resolution: Resolution,
     debug_node: DebugNode,
 }
 
@@ -25,6 +39,7 @@ impl Renderer {
         occluder_data: Vec<f32>,
         width: u32,
         height: u32,
+        palettes: &[[u32; PALETTE_SIZE]],
     ) -> anyhow::Result<Self> {
         let size = window.inner_size();
         // The instance is a handle to our GPU
@@ -94,12 +109,45 @@ impl Renderer {
         });
         let texture = Texture::from_data(&device, &queue, &occluder_data, width, height);
 
+        let palette = Palette::new(&device, &queue, palettes);
+
         let sdf_node = SDFPipeline::new(&device, texture);
-        let sprite_node =
-            SpriteNode::new(&device, &config, &queue, &sampler, &sdf_node.output_texture).await?;
+        let texture_atlas = TextureAtlas::new("test_texture-sheet.png", &device, &queue).await?;
+        let lighting = Lighting::new(
+            &device,
+            &sampler,
+            &sdf_node.output_texture,
+            config.width,
+            config.height,
+        );
+        let tile_animations = TileAnimations::new(&device);
+        let sprite_node = SpriteNode::new(
+            &device,
+            &config,
+            &sampler,
+            &texture_atlas,
+            &palette,
+            &tile_animations,
+        );
+        let deferred_lighting = DeferredLightingNode::new(&device, &config, &sampler, &lighting);
+        let particle_node =
+            ParticleNode::new(&device, sprite_node.particle_instance_buffer(), &lighting);
         let mut debug_node = DebugNode::new(&device, &config);
         debug_node.set_bind_group(&device, &sampler, &sdf_node.output_texture);
-        let output_node = OutputNode::new(&device, &config, &sampler, &sprite_node.texture);
+        let output_node = OutputNode::new(
+            &device,
+            &queue,
+            &config,
+            &sprite_node.texture,
+            &sprite_node.overlay_texture,
+        );
+        let overlay_camera = Camera::new(
+            &device,
+            size.height as f32,
+            size.width as f32,
+            1.0,
+            (size.width as f32 / 2., size.height as f32 / 2.),
+        );
 
         Ok(Self {
             surface,
@@ -107,9 +155,17 @@ impl Renderer {
             queue,
             config,
             sampler,
+            texture_atlas,
+            lighting,
+            tile_animations,
             sprite_node,
+            deferred_lighting,
+            tilemap_node: None,
+            particle_node,
+            overlay_camera,
             sdf_node,
             output_node,
+            resolution: Resolution::Window,
             debug_node,
         })
     }
@@ -124,27 +180,208 @@ impl Renderer {
             self.config.width = new_size.width;
             self.config.height = new_size.height;
             self.surface.configure(&self.device, &self.config);
+            self.output_node
+                .resize(&self.queue, new_size.width, new_size.height);
+
+            if self.resolution == Resolution::Window {
+                self.resize_targets();
+            }
+        }
+    }
+
+    /// Size in pixels of the texture the scene is drawn into.
+    pub fn target_size(&self) -> winit::dpi::PhysicalSize<u32> {
+        match self.resolution {
+            Resolution::Window => {
+                winit::dpi::PhysicalSize::new(self.config.width, self.config.height)
+            }
+            Resolution::Virtual { width, height } => winit::dpi::PhysicalSize::new(width, height),
+        }
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    /// Draw the scene at `resolution`, upscaled to the window with the current
+    /// [`UpscaleFilter`].
+    pub fn set_resolution(&mut self, resolution: Resolution) {
+        if resolution != self.resolution {
+            self.resolution = resolution;
+            self.resize_targets();
         }
     }
 
+    pub fn upscale_filter(&self) -> UpscaleFilter {
+        self.output_node.filter()
+    }
+
+    pub fn set_upscale_filter(&mut self, filter: UpscaleFilter) {
+        self.output_node.set_filter(&self.queue, filter);
+    }
+
+    fn resize_targets(&mut self) {
+        let size = self.target_size();
+        self.sprite_node
+            .resize_targets(&self.device, self.config.format, size.width, size.height);
+        self.deferred_lighting
+            .resize_targets(&self.device, &self.sampler, size.width, size.height);
+        self.lighting.resize(
+            &self.device,
+            &self.sampler,
+            &self.sdf_node.output_texture,
+            size.width,
+            size.height,
+        );
+        self.output_node.set_target(
+            &self.device,
+            &self.queue,
+            &self.sprite_node.texture,
+            &self.sprite_node.overlay_texture,
+        );
+
+        let (width, height) = (size.width as f32, size.height as f32);
+        self.overlay_camera
+            .update_view_projection(height, width, 0.);
+        self.overlay_camera.move_camera((width / 2., height / 2.));
+        self.queue.write_buffer(
+            &self.overlay_camera.uniform.buffer,
+            0,
+            bytemuck::cast_slice(&[self.overlay_camera.get_uniform().uniform]),
+        );
+    }
+
     // TODO: This should be generalized
-    pub fn draw_sprites(&mut self, sprites: &[SpriteInstance]) {
-        self.sprite_node.draw_sprites(sprites, &self.queue);
+    pub fn draw_sprites(
+        &mut self,
+        sprites: &[SpriteInstance],
+        dirty: &[Range<usize>],
+    ) -> anyhow::Result<()> {
+        self.sprite_node
+            .draw_sprites(sprites, dirty, &self.device, &self.queue)
+    }
+
+    pub fn draw_packed_sprites(
+        &mut self,
+        sprites: &[PackedSpriteInstance],
+        dirty: &[Range<usize>],
+    ) -> anyhow::Result<()> {
+        self.sprite_node
+            .draw_packed_sprites(sprites, dirty, &self.device, &self.queue)
+    }
+
+    pub fn draw_overlay_sprites(&mut self, sprites: &[SpriteInstance]) -> anyhow::Result<()> {
+        self.sprite_node
+            .write_overlay_sprites(sprites, &self.device, &self.queue)
+    }
+
+    pub fn write_static_sprites(&mut self, sprites: &[SpriteInstance]) -> anyhow::Result<()> {
+        self.sprite_node
+            .write_static_sprites(sprites, &self.device, &self.queue)
+    }
+
+    pub fn shrink_sprite_buffers(&mut self, min_capacity: usize) {
+        self.sprite_node
+            .shrink_instance_buffers(&self.device, &self.queue, min_capacity);
+    }
+
+    /// Instances a batch drawing from `buffer` can use, see [`SpriteNode::instance_count`].
+    pub fn sprite_count(&self, buffer: SpriteBuffer) -> u32 {
+        self.sprite_node.instance_count(buffer)
+    }
+
+    /// Most sprites `buffer` can hold, see [`SpriteNode::instance_limit`].
+    pub fn sprite_limit(&self, buffer: SpriteBuffer) -> usize {
+        self.sprite_node.instance_limit(buffer)
+    }
+
+    pub fn draw_lights(&mut self, lights: &[Light], dirty: &[Range<usize>]) -> anyhow::Result<()> {
+        self.lighting.draw_lights(
+            lights,
+            dirty,
+            &self.device,
+            &self.queue,
+            &self.sampler,
+            &self.sdf_node.output_texture,
+        )
+    }
+
+    /// Replace the animations tiles refer to by index, see
+    /// [`SpriteInstance::with_tile_animation`] and [`TilemapNode::animated_tile`].
+    pub fn write_tile_animations(&mut self, animations: &[TileAnimation]) -> anyhow::Result<()> {
+        self.tile_animations.write(animations, &self.queue)
+    }
+
+    /// Set the clock tile animations play from, in seconds.
+    pub fn set_animation_time(&mut self, time: f32) {
+        self.tile_animations.set_time(time, &self.queue);
+    }
+
+    pub fn add_emitter(&mut self, emitter: Emitter) -> anyhow::Result<EmitterId> {
+        self.particle_node.add_emitter(emitter, &self.queue)
+    }
+
+    pub fn remove_emitter(&mut self, id: EmitterId) -> bool {
+        self.particle_node.remove_emitter(
+            id,
+            self.sprite_node.particle_instance_buffer(),
+            &self.lighting,
+            &self.queue,
+        )
+    }
+
+    pub fn emitter_mut(&mut self, id: EmitterId) -> Option<&mut Emitter> {
+        self.particle_node.emitter_mut(id)
+    }
+
+    /// Advance every particle by `delta_time` seconds.
+    pub fn update_particles(&mut self, delta_time: f32) {
+        self.particle_node
+            .update(delta_time, &self.device, &self.queue);
     }
 
-    pub fn draw_lights(&mut self, lights: &[Light]) {
-        self.sprite_node.draw_lights(lights, &self.queue);
+    /// Instances to draw in a [`SpriteBuffer::Particles`] batch.
+    pub fn particle_count(&self) -> u32 {
+        self.particle_node.instance_count()
+    }
+
+    /// Replace the tilemap drawn under the sprites. See [`TilemapNode::new`] for the layout
+    /// of `tiles`.
+    pub fn write_tilemap(
+        &mut self,
+        tiles: &[u32],
+        width: u32,
+        height: u32,
+        tile_size: f32,
+        sprite_size: f32,
+        layer: f32,
+    ) {
+        self.tilemap_node = Some(TilemapNode::new(
+            &self.device,
+            &self.queue,
+            &self.texture_atlas,
+            &self.tile_animations,
+            tiles,
+            width,
+            height,
+            tile_size,
+            sprite_size,
+            layer,
+        ));
     }
 
     // TODO: Setup a render graph that processes nodes in a smoother way
     pub fn render(
         &mut self,
         camera: &Camera,
-        instances: u32,
+        batches: &[SpriteBatch],
+        show_tilemap: bool,
         show_debug_texture: bool,
     ) -> Result<(), wgpu::SurfaceError> {
         self.sdf_node.compute_pass(&self.device, &self.queue);
-        self.render_sprites_to_texture(camera, instances)?;
+        self.render_sprites_to_texture(camera, batches, show_tilemap)?;
+        self.output_node
+            .set_subpixel_offset(&self.queue, camera.subpixel_offset());
         self.render_to_screen(show_debug_texture)?;
 
         Ok(())
@@ -155,28 +392,75 @@ impl Renderer {
     pub fn render_sprites_to_texture(
         &mut self,
         camera: &Camera,
-        instances: u32,
+        batches: &[SpriteBatch],
+        show_tilemap: bool,
     ) -> Result<(), wgpu::SurfaceError> {
-        let view = &self.sprite_node.texture.view;
+        let depth_view = &self.sprite_node.depth_texture.view;
 
         let mut encoder = self
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Render sprites"),
             });
+        self.lighting.cull(&mut encoder, &self.queue, camera);
+        // The background is unlit, so it goes into the emissive target
         let clear_color = to_linear_rgb(0x0F0F26);
-        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-            label: Some("Base::pass"),
-            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                view: &view,
+        let gbuffer_attachment = |view, clear| {
+            Some(wgpu::RenderPassColorAttachment {
+                view,
                 resolve_target: None,
                 ops: wgpu::Operations {
-                    load: wgpu::LoadOp::Clear(wgpu::Color {
+                    load: wgpu::LoadOp::Clear(clear),
+                    store: wgpu::StoreOp::Store,
+                },
+            })
+        };
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("GBuffer::pass"),
+            color_attachments: &[
+                gbuffer_attachment(
+                    &self.deferred_lighting.albedo_texture.view,
+                    wgpu::Color::TRANSPARENT,
+                ),
+                gbuffer_attachment(
+                    &self.deferred_lighting.emissive_texture.view,
+                    wgpu::Color {
                         r: clear_color[0] as f64,
                         g: clear_color[1] as f64,
                         b: clear_color[2] as f64,
                         a: 1.0,
-                    }),
+                    },
+                ),
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        if let Some(tilemap_node) = self.tilemap_node.as_ref().filter(|_| show_tilemap) {
+            pass.draw_tilemap(tilemap_node, camera);
+        }
+        let (overlay_batches, scene_batches): (Vec<_>, Vec<_>) = batches
+            .iter()
+            .partition(|batch| batch.buffer == SpriteBuffer::Overlay);
+        pass.draw_sprite_batches(&self.sprite_node, camera, &scene_batches);
+        drop(pass);
+
+        // Light the whole G-buffer at once, into the texture the output node reads
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Lighting::pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &self.sprite_node.texture.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
                     store: wgpu::StoreOp::Store,
                 },
             })],
@@ -184,8 +468,33 @@ impl Renderer {
             timestamp_writes: None,
             occlusion_query_set: None,
         });
+        pass.draw_lighting(&self.deferred_lighting, camera, &self.lighting);
+        drop(pass);
 
-        pass.draw_sprites_instanced(&self.sprite_node, camera, instances);
+        // The overlay gets its own texture, composited after the scene has been shifted by the
+        // camera's sub-pixel offset
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Overlay::pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &self.sprite_node.overlay_texture.view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        pass.draw_sprite_batches(&self.sprite_node, &self.overlay_camera, &overlay_batches);
         drop(pass);
 
         self.queue.submit(Some(encoder.finish()));
@@ -0,0 +1,113 @@
+use std::ops::Range;
+
+use super::{camera::Bounds, SpriteInstance};
+
+/// Spatial grid over sprites that don't move, used to only draw the ones the camera sees.
+///
+/// Building the grid sorts the instances by cell, row by row, so the visible cells of a
+/// row are one contiguous range of instances that can be drawn with a single call.
+pub struct SpriteGrid {
+    cell_size: f32,
+    // Cell coordinates of the bottom left cell
+    origin: [i32; 2],
+    columns: usize,
+    rows: usize,
+    // Instances of cell i are cell_starts[i]..cell_starts[i + 1]
+    cell_starts: Vec<u32>,
+    // Largest sprite extent, sprites can reach this far outside of their cell
+    margin: f32,
+}
+
+impl SpriteGrid {
+    /// Sorts `instances` in place, upload them after building the grid.
+    pub fn build(instances: &mut [SpriteInstance], cell_size: f32) -> Self {
+        let cell_of = |instance: &SpriteInstance| {
+            [
+                (instance.translation.x() / cell_size).floor() as i32,
+                (instance.translation.y() / cell_size).floor() as i32,
+            ]
+        };
+
+        let mut min = [i32::MAX; 2];
+        let mut max = [i32::MIN; 2];
+        let mut margin: f32 = 0.;
+        for instance in instances.iter() {
+            let cell = cell_of(instance);
+            for axis in 0..2 {
+                min[axis] = min[axis].min(cell[axis]);
+                max[axis] = max[axis].max(cell[axis]);
+            }
+            // A rotated quad can reach as far as its diagonal
+            let size = [
+                instance.size.width() * instance.scale.x(),
+                instance.size.height() * instance.scale.y(),
+            ];
+            margin = margin.max((size[0] * size[0] + size[1] * size[1]).sqrt());
+        }
+        if instances.is_empty() {
+            min = [0; 2];
+            max = [-1; 2];
+        }
+
+        let columns = (max[0] - min[0] + 1) as usize;
+        let rows = (max[1] - min[1] + 1) as usize;
+        let cell_index =
+            |cell: [i32; 2]| (cell[1] - min[1]) as usize * columns + (cell[0] - min[0]) as usize;
+
+        instances.sort_by_key(|instance| cell_index(cell_of(instance)));
+
+        let mut cell_starts = vec![0u32; columns * rows + 1];
+        for instance in instances.iter() {
+            cell_starts[cell_index(cell_of(instance)) + 1] += 1;
+        }
+        for i in 1..cell_starts.len() {
+            cell_starts[i] += cell_starts[i - 1];
+        }
+
+        Self {
+            cell_size,
+            origin: min,
+            columns,
+            rows,
+            cell_starts,
+            margin,
+        }
+    }
+
+    /// Instance ranges of the cells overlapping `bounds`, one per visible row at most.
+    pub fn visible_ranges(&self, bounds: &Bounds) -> Vec<Range<u32>> {
+        if self.columns == 0 || self.rows == 0 {
+            return Vec::new();
+        }
+
+        let bounds = bounds.expand(self.margin);
+        let to_cell = |value: f32, axis: usize, cells: usize| {
+            ((value / self.cell_size).floor() as i64 - self.origin[axis] as i64)
+                .clamp(-1, cells as i64)
+        };
+        let min_column = to_cell(bounds.min[0], 0, self.columns).max(0);
+        let max_column = to_cell(bounds.max[0], 0, self.columns).min(self.columns as i64 - 1);
+        let min_row = to_cell(bounds.min[1], 1, self.rows).max(0);
+        let max_row = to_cell(bounds.max[1], 1, self.rows).min(self.rows as i64 - 1);
+
+        let mut ranges: Vec<Range<u32>> = Vec::new();
+        if min_column > max_column {
+            return ranges;
+        }
+        for row in min_row..=max_row {
+            let first = row as usize * self.columns + min_column as usize;
+            let last = row as usize * self.columns + max_column as usize;
+            let range = self.cell_starts[first]..self.cell_starts[last + 1];
+            if range.is_empty() {
+                continue;
+            }
+            // Rows spanning the whole grid join up with the previous row
+            match ranges.last_mut() {
+                Some(previous) if previous.end == range.start => previous.end = range.end,
+                _ => ranges.push(range),
+            }
+        }
+
+        ranges
+    }
+}
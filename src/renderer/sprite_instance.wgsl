@@ -0,0 +1,5 @@
+
+@vertex
+fn vs_main(input: VertexInput, ins: InstanceInput) -> VertexOutput {
+	return sprite_vertex(input, ins);
+}
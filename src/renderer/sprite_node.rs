@@ -1,70 +1,74 @@
-use wgpu::{include_wgsl, util::DeviceExt};
+use std::ops::Range;
+
+use wgpu::util::DeviceExt;
 
 use super::{
-    camera::Camera, pipeline_utils::create_render_pipeline, texture_atlas::TextureAtlas, Texture,
+    camera::{Camera, MARGIN},
+    deferred_lighting::DeferredLightingNode,
+    instance_buffer::InstanceBuffer,
+    palette::Palette,
+    particles::MAX_PARTICLES,
+    pipeline_utils::create_multi_target_pipeline,
+    texture_atlas::TextureAtlas,
+    tile_animation::TileAnimations,
+    PackedSpriteInstance, Texture,
 };
 
 pub struct SpriteNode {
-    pipeline: wgpu::RenderPipeline,
+    pipelines: BlendPipelines,
+    packed_pipelines: BlendPipelines,
+    overlay_pipelines: BlendPipelines,
     vertex_buffer: wgpu::Buffer,
     index_buffer: wgpu::Buffer,
-    instance_buffer: wgpu::Buffer,
+    instance_buffer: InstanceBuffer<SpriteInstance>,
+    static_instance_buffer: InstanceBuffer<SpriteInstance>,
+    packed_instance_buffer: InstanceBuffer<PackedSpriteInstance>,
+    overlay_instance_buffer: InstanceBuffer<SpriteInstance>,
+    // Written by the particle compute pass, one instance per particle
+    particle_instance_buffer: wgpu::Buffer,
     sampler_bind_group: wgpu::BindGroup,
     texture_atlas_bind_group: wgpu::BindGroup,
-    lights_bind_group: wgpu::BindGroup,
-    lights_buffer: wgpu::Buffer,
     pub texture: Texture,
+    // Overlay sprites are drawn apart so they don't move with the camera's sub-pixel offset
+    pub overlay_texture: Texture,
+    pub depth_texture: Texture,
 }
 
-// TODO: clean up the whole lights buffer stuff, pretty hacky right now
+const INITIAL_INSTANCE_CAPACITY: usize = 4096;
+// Hard limit on the number of sprite instances, submitting more is an error
+pub const MAX_SPRITE_INSTANCES: usize = 1_200_000;
+
 impl SpriteNode {
-    pub async fn new(
+    pub fn new(
         device: &wgpu::Device,
         config: &wgpu::SurfaceConfiguration,
-        queue: &wgpu::Queue,
         sampler: &wgpu::Sampler,
-        sdf_texture: &Texture,
-    ) -> anyhow::Result<Self> {
-        // Texture atlas
-        let texture_atlas = TextureAtlas::new("test_texture-sheet.png", device, &queue).await?;
-        let texture = Texture::create_2d_texture(
-            device,
-            config.width,
-            config.height,
-            config.format,
-            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
-            Some("sprite texture"),
-        );
+        texture_atlas: &TextureAtlas,
+        palette: &Palette,
+        tile_animations: &TileAnimations,
+    ) -> Self {
+        let (texture, overlay_texture, depth_texture) =
+            Self::create_targets(device, config.format, config.width, config.height);
 
         // Layouts
+        let [animations_entry, time_entry] = TileAnimations::bind_group_layout_entries();
         let texture_atlas_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("texture atlas bind group"),
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
-            });
-
-        let lights_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                label: Some("lights bind group layout"),
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Storage { read_only: true },
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
+                    animations_entry,
+                    time_entry,
+                ],
             });
 
         let sampler_bind_group_layout =
@@ -77,23 +81,99 @@ impl SpriteNode {
                 &sampler_bind_group_layout,
                 &texture_atlas_bind_group_layout,
                 &Camera::create_bind_group_layout(device),
-                &lights_bind_group_layout,
             ],
             push_constant_ranges: &[],
         });
 
-        // Shader
-        let shader = include_wgsl!("texture_atlas_shader.wgsl");
-
-        // Pipeline
-        let pipeline = create_render_pipeline(
-            device,
-            &pipeline_layout,
-            config.format,
-            &[Vertex::desc(), SpriteInstance::desc()],
-            wgpu::PrimitiveTopology::TriangleList,
-            shader,
-            Some("sprite renderer pipeline"),
+        // Pipelines, one per blend mode for each instance layout, and one more for the
+        // translucent texels of alpha blended sprites. The layouts only differ in the vertex
+        // entry point that unpacks the instance. Scene sprites write the G-buffer, overlay
+        // sprites are drawn unlit into a single target.
+        let create_pipeline = |blend_mode: BlendMode,
+                               pass: SpritePass,
+                               instance_layout: wgpu::VertexBufferLayout<'static>,
+                               entry_point: &str,
+                               overlay: bool,
+                               label: &str| {
+            let blend = Some(blend_mode.blend_state());
+            let (targets, fragment_entry_point) = if overlay {
+                let target = wgpu::ColorTargetState {
+                    format: config.format,
+                    blend,
+                    write_mask: wgpu::ColorWrites::ALL,
+                };
+                (vec![Some(target)], "fs_overlay")
+            } else {
+                (
+                    DeferredLightingNode::color_targets(blend).to_vec(),
+                    blend_mode.fragment_entry_point(),
+                )
+            };
+            create_multi_target_pipeline(
+                device,
+                &pipeline_layout,
+                &targets,
+                fragment_entry_point,
+                Some(blend_mode.depth_stencil_state(pass)),
+                &[Vertex::desc(), instance_layout],
+                wgpu::PrimitiveTopology::TriangleList,
+                wgpu::ShaderModuleDescriptor {
+                    label: Some("sprite shader"),
+                    source: wgpu::ShaderSource::Wgsl(
+                        [
+                            TileAnimations::SHADER,
+                            include_str!("texture_atlas_shader.wgsl"),
+                            entry_point,
+                            &format!(
+                                "const ALPHA_TEST: u32 = {}u;\n",
+                                blend_mode.alpha_test(pass)
+                            ),
+                        ]
+                        .concat()
+                        .into(),
+                    ),
+                },
+                Some(&format!("{label} {blend_mode:?} {pass:?} pipeline")),
+            )
+        };
+        let create_pipelines = |instance_layout: wgpu::VertexBufferLayout<'static>,
+                                entry_point: &str,
+                                overlay,
+                                label| {
+            let create = |blend_mode, pass| {
+                create_pipeline(
+                    blend_mode,
+                    pass,
+                    instance_layout.clone(),
+                    entry_point,
+                    overlay,
+                    label,
+                )
+            };
+            BlendPipelines {
+                alpha: create(BlendMode::Alpha, SpritePass::Opaque),
+                translucent: create(BlendMode::Alpha, SpritePass::Translucent),
+                additive: create(BlendMode::Additive, SpritePass::Translucent),
+                multiply: create(BlendMode::Multiply, SpritePass::Translucent),
+            }
+        };
+        let pipelines = create_pipelines(
+            SpriteInstance::desc(),
+            include_str!("sprite_instance.wgsl"),
+            false,
+            "sprite renderer",
+        );
+        let packed_pipelines = create_pipelines(
+            PackedSpriteInstance::desc(),
+            include_str!("packed_sprite_instance.wgsl"),
+            false,
+            "sprite renderer packed",
+        );
+        let overlay_pipelines = create_pipelines(
+            SpriteInstance::desc(),
+            include_str!("sprite_instance.wgsl"),
+            true,
+            "sprite renderer overlay",
         );
 
         // Buffers
@@ -109,12 +189,36 @@ impl SpriteNode {
             usage: wgpu::BufferUsages::INDEX,
         });
 
-        let instance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Instance Buffer"),
-            size: 1_200_000 * std::mem::size_of::<SpriteInstance>() as u64,
+        let instance_buffer = InstanceBuffer::new(
+            device,
+            "Instance Buffer",
+            INITIAL_INSTANCE_CAPACITY,
+            MAX_SPRITE_INSTANCES,
+        );
+        let static_instance_buffer = InstanceBuffer::new(
+            device,
+            "Static Instance Buffer",
+            INITIAL_INSTANCE_CAPACITY,
+            MAX_SPRITE_INSTANCES,
+        );
+        let packed_instance_buffer = InstanceBuffer::new(
+            device,
+            "Packed Instance Buffer",
+            INITIAL_INSTANCE_CAPACITY,
+            MAX_SPRITE_INSTANCES,
+        );
+        let overlay_instance_buffer = InstanceBuffer::new(
+            device,
+            "Overlay Instance Buffer",
+            INITIAL_INSTANCE_CAPACITY,
+            MAX_SPRITE_INSTANCES,
+        );
+        let particle_instance_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Particle Instance Buffer"),
+            size: (MAX_PARTICLES * std::mem::size_of::<SpriteInstance>()) as u64,
             usage: wgpu::BufferUsages::VERTEX
-                | wgpu::BufferUsages::COPY_DST
-                | wgpu::BufferUsages::COPY_SRC,
+                | wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
         let texture_atlas_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
@@ -125,15 +229,6 @@ impl SpriteNode {
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
-        let lights_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("Lights Buffer"),
-            size: 100 * std::mem::size_of::<Light>() as u64,
-            usage: wgpu::BufferUsages::STORAGE
-                | wgpu::BufferUsages::COPY_DST
-                | wgpu::BufferUsages::UNIFORM,
-            mapped_at_creation: false,
-        });
-
         // Bind groups
         // let sampler_bind_group = create_basic_sampler_bind_group(
         //     &device,
@@ -147,38 +242,90 @@ impl SpriteNode {
             &device,
             &sampler,
             &sampler_bind_group_layout,
-            &texture_atlas.texture,
-            &sdf_texture,
+            texture_atlas,
+            palette,
             Some("sprite bg"),
         );
-        let lights_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("lights bind group"),
-            layout: &lights_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: lights_buffer.as_entire_binding(),
-            }],
-        });
+        let [animations_entry, time_entry] = tile_animations.bind_group_entries();
         let texture_atlas_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("sprite renderer texture atlas Bind Group"),
             layout: &texture_atlas_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: texture_atlas_buffer.as_entire_binding(),
-            }],
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: texture_atlas_buffer.as_entire_binding(),
+                },
+                animations_entry,
+                time_entry,
+            ],
         });
 
-        Ok(Self {
-            pipeline,
+        Self {
+            pipelines,
+            packed_pipelines,
+            overlay_pipelines,
             vertex_buffer,
             index_buffer,
             instance_buffer,
+            static_instance_buffer,
+            packed_instance_buffer,
+            overlay_instance_buffer,
+            particle_instance_buffer,
             sampler_bind_group,
             texture_atlas_bind_group,
-            lights_bind_group,
-            lights_buffer,
             texture,
-        })
+            overlay_texture,
+            depth_texture,
+        }
+    }
+
+    fn instance_slice(&self, buffer: SpriteBuffer) -> wgpu::BufferSlice<'_> {
+        match buffer {
+            SpriteBuffer::Static => self.static_instance_buffer.slice(),
+            SpriteBuffer::Dynamic => self.instance_buffer.slice(),
+            SpriteBuffer::Packed => self.packed_instance_buffer.slice(),
+            SpriteBuffer::Overlay => self.overlay_instance_buffer.slice(),
+            SpriteBuffer::Particles => self.particle_instance_buffer.slice(..),
+        }
+    }
+
+    /// Instances uploaded to `buffer`. A write that hit the buffer's limit leaves the previous
+    /// contents in place, so batches are drawn from this rather than the CPU side length.
+    pub fn instance_count(&self, buffer: SpriteBuffer) -> u32 {
+        let len = match buffer {
+            SpriteBuffer::Static => self.static_instance_buffer.len(),
+            SpriteBuffer::Dynamic => self.instance_buffer.len(),
+            SpriteBuffer::Packed => self.packed_instance_buffer.len(),
+            SpriteBuffer::Overlay => self.overlay_instance_buffer.len(),
+            SpriteBuffer::Particles => MAX_PARTICLES,
+        };
+        len as u32
+    }
+
+    /// Most instances `buffer` can hold, writing more than that fails.
+    pub fn instance_limit(&self, buffer: SpriteBuffer) -> usize {
+        match buffer {
+            SpriteBuffer::Static => self.static_instance_buffer.max_capacity(),
+            SpriteBuffer::Dynamic => self.instance_buffer.max_capacity(),
+            SpriteBuffer::Packed => self.packed_instance_buffer.max_capacity(),
+            SpriteBuffer::Overlay => self.overlay_instance_buffer.max_capacity(),
+            SpriteBuffer::Particles => MAX_PARTICLES,
+        }
+    }
+
+    pub(super) fn particle_instance_buffer(&self) -> &wgpu::Buffer {
+        &self.particle_instance_buffer
+    }
+
+    fn pipeline(&self, batch: &SpriteBatch, pass: SpritePass) -> &wgpu::RenderPipeline {
+        let pipelines = match batch.buffer {
+            SpriteBuffer::Static | SpriteBuffer::Dynamic | SpriteBuffer::Particles => {
+                &self.pipelines
+            }
+            SpriteBuffer::Packed => &self.packed_pipelines,
+            SpriteBuffer::Overlay => &self.overlay_pipelines,
+        };
+        pipelines.get(batch.blend_mode, pass)
     }
 
     fn get_bind_group_layout(device: &wgpu::Device, label: Option<&str>) -> wgpu::BindGroupLayout {
@@ -201,6 +348,7 @@ impl SpriteNode {
                     ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                     count: None,
                 },
+                // Atlas viewed without srgb decoding, for reading palette indices
                 wgpu::BindGroupLayoutEntry {
                     binding: 2,
                     visibility: wgpu::ShaderStages::FRAGMENT,
@@ -211,6 +359,16 @@ impl SpriteNode {
                     },
                     count: None,
                 },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
             ],
         })
     }
@@ -219,8 +377,8 @@ impl SpriteNode {
         device: &wgpu::Device,
         sampler: &wgpu::Sampler,
         layout: &wgpu::BindGroupLayout,
-        sprite_texture: &Texture,
-        sdf_texture: &Texture,
+        texture_atlas: &TextureAtlas,
+        palette: &Palette,
         label: Option<&str>,
     ) -> wgpu::BindGroup {
         device.create_bind_group(&wgpu::BindGroupDescriptor {
@@ -229,7 +387,7 @@ impl SpriteNode {
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&sprite_texture.view),
+                    resource: wgpu::BindingResource::TextureView(&texture_atlas.texture.view),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
@@ -237,40 +395,143 @@ impl SpriteNode {
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&sdf_texture.view),
+                    resource: wgpu::BindingResource::TextureView(&texture_atlas.indexed_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&palette.texture.view),
                 },
             ],
         })
     }
 
+    /// Upload the ranges of `sprites` that changed since the last call.
     pub fn draw_sprites(
         &mut self,
         sprites: &[SpriteInstance],
+        dirty: &[Range<usize>],
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> anyhow::Result<()> {
+        self.instance_buffer
+            .write_ranges(device, queue, sprites, dirty)
+    }
+
+    /// Like [`draw_sprites`](Self::draw_sprites), for sprites in the packed layout.
+    pub fn draw_packed_sprites(
+        &mut self,
+        sprites: &[PackedSpriteInstance],
+        dirty: &[Range<usize>],
+        device: &wgpu::Device,
         queue: &wgpu::Queue,
-        // offset_base: u64,
+    ) -> anyhow::Result<()> {
+        self.packed_instance_buffer
+            .write_ranges(device, queue, sprites, dirty)
+    }
+
+    /// Replace the sprites drawn in screen space, see [`SpriteBuffer::Overlay`].
+    pub fn write_overlay_sprites(
+        &mut self,
+        sprites: &[SpriteInstance],
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> anyhow::Result<()> {
+        self.overlay_instance_buffer.write(device, queue, sprites)
+    }
+
+    /// Upload sprites that don't change, like the tilemap. Only needs to be called again
+    /// when the static sprites are replaced.
+    pub fn write_static_sprites(
+        &mut self,
+        sprites: &[SpriteInstance],
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+    ) -> anyhow::Result<()> {
+        self.static_instance_buffer.write(device, queue, sprites)
+    }
+
+    // The textures are larger than the view by the camera margin on every side
+    fn create_targets(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
+    ) -> (Texture, Texture, Texture) {
+        let width = width + 2 * MARGIN;
+        let height = height + 2 * MARGIN;
+        let create_color_texture = |label| {
+            Texture::create_2d_texture(
+                device,
+                width,
+                height,
+                format,
+                wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::RENDER_ATTACHMENT,
+                Some(label),
+            )
+        };
+        let depth_texture =
+            Texture::create_depth_texture(device, width, height, Some("sprite depth texture"));
+        (
+            create_color_texture("sprite texture"),
+            create_color_texture("sprite overlay texture"),
+            depth_texture,
+        )
+    }
+
+    /// Recreate the textures the sprites are drawn into for a view of `width` by `height`.
+    pub fn resize_targets(
+        &mut self,
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        width: u32,
+        height: u32,
     ) {
-        queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&sprites));
-        // self.instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        //     label: Some("Instance Buffer"),
-        //     contents: bytemuck::cast_slice(&sprites),
-        //     usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-        // });
+        (self.texture, self.overlay_texture, self.depth_texture) =
+            Self::create_targets(device, format, width, height);
     }
 
-    // TODO: This should be generalized
-    pub fn draw_lights(&mut self, lights: &[Light], queue: &wgpu::Queue) {
-        queue.write_buffer(&self.lights_buffer, 0, bytemuck::cast_slice(&lights));
+    /// Release dynamic and packed instance buffer memory that is no longer used, keeping room
+    /// for at least `min_capacity` sprites in each.
+    pub fn shrink_instance_buffers(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        min_capacity: usize,
+    ) {
+        self.instance_buffer.shrink_to(device, queue, min_capacity);
+        self.packed_instance_buffer
+            .shrink_to(device, queue, min_capacity);
     }
 }
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-pub struct Light {
-    pub position: [f32; 2],
-    pub intensity: f32,
-    pub falloff: f32,
-    pub color: [f32; 3],
-    pub frequency: f32,
+struct BlendPipelines {
+    // Opaque texels of alpha blended sprites, writing depth
+    alpha: wgpu::RenderPipeline,
+    // The rest of the texels of alpha blended sprites
+    translucent: wgpu::RenderPipeline,
+    additive: wgpu::RenderPipeline,
+    multiply: wgpu::RenderPipeline,
+}
+
+impl BlendPipelines {
+    fn get(&self, blend_mode: BlendMode, pass: SpritePass) -> &wgpu::RenderPipeline {
+        match (blend_mode, pass) {
+            (BlendMode::Alpha, SpritePass::Opaque) => &self.alpha,
+            (BlendMode::Alpha, SpritePass::Translucent) => &self.translucent,
+            (BlendMode::Additive, _) => &self.additive,
+            (BlendMode::Multiply, _) => &self.multiply,
+        }
+    }
+}
+
+/// Alpha blended batches are drawn in two passes. The opaque pass draws only their fully
+/// opaque texels and writes depth. The translucent pass draws the rest without writing
+/// depth, so it blends over whatever is behind even when that was submitted later. The
+/// other blend modes are only drawn in the translucent pass.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(super) enum SpritePass {
+    Opaque,
+    Translucent,
 }
 
 #[repr(C)]
@@ -281,7 +542,7 @@ struct TextureAtlasUniform {
 
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
-pub(crate) struct Wrapped2D([f32; 2]);
+pub struct Wrapped2D([f32; 2]);
 
 impl Wrapped2D {
     pub fn x(&self) -> f32 {
@@ -318,11 +579,45 @@ pub struct SpriteInstance {
     pub size: Wrapped2D,
     pub texture_origin: Wrapped2D,
     pub translation: Wrapped2D,
+    // Multiplier on size, giving the size of the quad in world units. Should be positive,
+    // use the flip flags to mirror a sprite.
+    pub scale: Wrapped2D,
+    // Point to rotate around, relative to the quad. (0, 0) is bottom left, (1, 1) top right
+    pub pivot: Wrapped2D,
+    // Counter clockwise rotation in radians
+    pub rotation: f32,
+    // Sprites on higher layers are drawn on top. Within a layer, sprites are y-sorted
+    // on their translation, lower on screen is drawn on top. Range is 0..=MAX_LAYER
+    pub layer: f32,
+    // Multiplied into the sprite colour, alpha controls the opacity
+    pub tint: [f32; 4],
+    // Palette row used to colour an indexed sprite, or NO_PALETTE for regular sprites
+    pub palette: u32,
+    pub flags: u32,
 }
 
 impl SpriteInstance {
-    const ATTRIBS: [wgpu::VertexAttribute; 3] =
-        wgpu::vertex_attr_array![2 => Float32x2, 3 => Float32x2, 4 => Float32x2];
+    pub const NO_PALETTE: u32 = u32::MAX;
+    pub const MAX_LAYER: f32 = 32.;
+    pub const FLIP_X: u32 = 1 << 0;
+    pub const FLIP_Y: u32 = 1 << 1;
+    // Skip the scene lighting, for text and other overlays
+    pub const UNLIT: u32 = 1 << 2;
+    // Show a frame of a tile animation instead of texture_origin, see with_tile_animation
+    pub const ANIMATED: u32 = 1 << 3;
+
+    const ATTRIBS: [wgpu::VertexAttribute; 10] = wgpu::vertex_attr_array![
+        2 => Float32x2,
+        3 => Float32x2,
+        4 => Float32x2,
+        5 => Float32x2,
+        6 => Float32x2,
+        7 => Float32,
+        8 => Float32,
+        9 => Float32x4,
+        10 => Uint32,
+        11 => Uint32
+    ];
     fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
         wgpu::VertexBufferLayout {
             array_stride: std::mem::size_of::<SpriteInstance>() as wgpu::BufferAddress,
@@ -336,6 +631,84 @@ impl SpriteInstance {
             size: Wrapped2D::new(size),
             texture_origin: Wrapped2D::new(texture_origin),
             translation: Wrapped2D::new(translation),
+            scale: Wrapped2D::new([1., 1.]),
+            pivot: Wrapped2D::new([0.5, 0.5]),
+            rotation: 0.,
+            layer: 0.,
+            tint: [1., 1., 1., 1.],
+            palette: Self::NO_PALETTE,
+            flags: 0,
+        }
+    }
+
+    /// Render the atlas region as palette indices, coloured by the given palette row.
+    pub fn with_palette(mut self, row: u32) -> Self {
+        self.palette = row;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: [f32; 2]) -> Self {
+        self.scale = Wrapped2D::new(scale);
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: f32, pivot: [f32; 2]) -> Self {
+        self.rotation = rotation;
+        self.pivot = Wrapped2D::new(pivot);
+        self
+    }
+
+    pub fn with_layer(mut self, layer: f32) -> Self {
+        self.layer = layer;
+        self
+    }
+
+    pub fn with_tint(mut self, tint: [f32; 4]) -> Self {
+        self.tint = tint;
+        self
+    }
+
+    pub fn with_flip(mut self, flip_x: bool, flip_y: bool) -> Self {
+        self.set_flip(flip_x, flip_y);
+        self
+    }
+
+    /// Play a tile animation registered with the renderer, `phase` shifts it by whole frames.
+    /// The packed layout has no room for this, packed sprites keep their texture origin.
+    pub fn with_tile_animation(mut self, animation: u32, phase: u32) -> Self {
+        self.flags = (self.flags & 0xFF) | Self::ANIMATED | (animation & 0xFF) << 8;
+        self.flags |= (phase & 0xFF) << 16;
+        self
+    }
+
+    /// Whether the scene lights affect the sprite. Sprites are lit by default.
+    pub fn with_lighting(mut self, lit: bool) -> Self {
+        self.set_flag(Self::UNLIT, !lit);
+        self
+    }
+
+    pub fn set_flip(&mut self, flip_x: bool, flip_y: bool) {
+        self.set_flag(Self::FLIP_X, flip_x);
+        self.set_flag(Self::FLIP_Y, flip_y);
+    }
+
+    pub fn set_translation(&mut self, translation: [f32; 2]) {
+        self.translation = Wrapped2D::new(translation);
+    }
+
+    pub fn set_texture_origin(&mut self, texture_origin: [f32; 2]) {
+        self.texture_origin = Wrapped2D::new(texture_origin);
+    }
+
+    pub fn set_flip_x(&mut self, flip_x: bool) {
+        self.set_flag(Self::FLIP_X, flip_x);
+    }
+
+    fn set_flag(&mut self, flag: u32, enabled: bool) {
+        if enabled {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
         }
     }
 
@@ -389,12 +762,128 @@ const VERTICES: &[Vertex] = &[
 
 const INDICES: &[u16] = &[2, 1, 0u16, 2, 3, 1];
 
+/// How a batch of sprites is combined with what has already been drawn.
+///
+/// Only the opaque texels of alpha blended sprites write depth, see [`SpritePass`]. Everything
+/// translucent blends in submission order, after all opaque texels have been drawn.
+/// Multiplied sprites are never lit, they tint the lit scene behind them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlendMode {
+    Alpha,
+    Additive,
+    Multiply,
+}
+
+impl BlendMode {
+    // The sprite shader outputs premultiplied alpha, so all modes are expressed in those terms
+    fn blend_state(&self) -> wgpu::BlendState {
+        let keep_alpha = wgpu::BlendComponent {
+            src_factor: wgpu::BlendFactor::Zero,
+            dst_factor: wgpu::BlendFactor::One,
+            operation: wgpu::BlendOperation::Add,
+        };
+        match self {
+            BlendMode::Alpha => wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+            BlendMode::Additive => wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::One,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: keep_alpha,
+            },
+            BlendMode::Multiply => wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::Dst,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: keep_alpha,
+            },
+        }
+    }
+}
+
+impl BlendMode {
+    // Fragment entry point writing the G-buffer, see texture_atlas_shader.wgsl
+    fn fragment_entry_point(&self) -> &'static str {
+        match self {
+            BlendMode::Alpha | BlendMode::Additive => "fs_main",
+            BlendMode::Multiply => "fs_multiply",
+        }
+    }
+
+    // Which texels the pipeline keeps, must match the ALPHA_TEST_* constants in
+    // texture_atlas_shader.wgsl
+    fn alpha_test(&self, pass: SpritePass) -> u32 {
+        match (self, pass) {
+            (BlendMode::Alpha, SpritePass::Opaque) => 1,
+            (BlendMode::Alpha, SpritePass::Translucent) => 2,
+            _ => 0,
+        }
+    }
+
+    fn depth_stencil_state(&self, pass: SpritePass) -> wgpu::DepthStencilState {
+        wgpu::DepthStencilState {
+            format: Texture::DEPTH_FORMAT,
+            depth_write_enabled: *self == BlendMode::Alpha && pass == SpritePass::Opaque,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }
+    }
+}
+
+/// Which instance buffer a batch draws from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SpriteBuffer {
+    // Written once, see SpriteNode::write_static_sprites
+    Static,
+    // Updated every frame with the ranges that changed
+    Dynamic,
+    // Like Dynamic, holding PackedSpriteInstances
+    Packed,
+    // Drawn in screen space instead of through the world camera, in pixels from the bottom
+    // left corner of the window. Replaced as a whole by SpriteNode::write_overlay_sprites.
+    // Never lit
+    Overlay,
+    // Filled on the GPU by the ParticleNode
+    Particles,
+}
+
+/// A range of instances in one of the instance buffers drawn with the same blend mode.
+#[derive(Clone, Debug)]
+pub struct SpriteBatch {
+    pub buffer: SpriteBuffer,
+    pub instances: Range<u32>,
+    pub blend_mode: BlendMode,
+}
+
+impl SpriteBatch {
+    pub fn new(buffer: SpriteBuffer, instances: Range<u32>, blend_mode: BlendMode) -> Self {
+        Self {
+            buffer,
+            instances,
+            blend_mode,
+        }
+    }
+}
+
 pub(super) trait DrawSprite<'a> {
     fn draw_sprites_instanced(
         &mut self,
         sprite_renderer: &'a SpriteNode,
         camera: &'a Camera,
-        instances: u32,
+        batch: &SpriteBatch,
+        pass: SpritePass,
+    );
+
+    /// Draw `batches` in order, in an opaque and then a translucent pass, see [`SpritePass`].
+    fn draw_sprite_batches(
+        &mut self,
+        sprite_renderer: &'a SpriteNode,
+        camera: &'a Camera,
+        batches: &[&SpriteBatch],
     );
 }
 
@@ -406,11 +895,12 @@ where
         &mut self,
         sprite_renderer: &'b SpriteNode,
         camera: &'b Camera,
-        instances: u32,
+        batch: &SpriteBatch,
+        pass: SpritePass,
     ) {
-        self.set_pipeline(&sprite_renderer.pipeline);
+        self.set_pipeline(sprite_renderer.pipeline(batch, pass));
         self.set_vertex_buffer(0, sprite_renderer.vertex_buffer.slice(..));
-        self.set_vertex_buffer(1, sprite_renderer.instance_buffer.slice(..));
+        self.set_vertex_buffer(1, sprite_renderer.instance_slice(batch.buffer));
         self.set_index_buffer(
             sprite_renderer.index_buffer.slice(..),
             wgpu::IndexFormat::Uint16,
@@ -418,7 +908,32 @@ where
         self.set_bind_group(0, &sprite_renderer.sampler_bind_group, &[]);
         self.set_bind_group(1, &sprite_renderer.texture_atlas_bind_group, &[]);
         self.set_bind_group(2, &camera.bind_group(), &[]);
-        self.set_bind_group(3, &sprite_renderer.lights_bind_group, &[]);
-        self.draw_indexed(0..INDICES.len() as u32, 0, 0..instances)
+        // Never read past what was uploaded, even if the batch asks for more
+        let end = batch
+            .instances
+            .end
+            .min(sprite_renderer.instance_count(batch.buffer));
+        self.draw_indexed(
+            0..INDICES.len() as u32,
+            0,
+            batch.instances.start.min(end)..end,
+        )
+    }
+
+    fn draw_sprite_batches(
+        &mut self,
+        sprite_renderer: &'b SpriteNode,
+        camera: &'b Camera,
+        batches: &[&SpriteBatch],
+    ) {
+        for batch in batches
+            .iter()
+            .filter(|batch| batch.blend_mode == BlendMode::Alpha)
+        {
+            self.draw_sprites_instanced(sprite_renderer, camera, batch, SpritePass::Opaque);
+        }
+        for batch in batches {
+            self.draw_sprites_instanced(sprite_renderer, camera, batch, SpritePass::Translucent);
+        }
     }
 }
@@ -10,6 +10,8 @@ pub struct Texture {
 }
 
 impl Texture {
+    pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
     pub fn from_bytes(
         device: &wgpu::Device,
         queue: &wgpu::Queue,
@@ -42,6 +44,12 @@ impl Texture {
         } else {
             wgpu::TextureFormat::Rgba8UnormSrgb
         };
+        // srgb textures can also be viewed as raw bytes, which indexed (palette) sprites need
+        let view_formats: &[wgpu::TextureFormat] = if is_normal_map {
+            &[]
+        } else {
+            &[wgpu::TextureFormat::Rgba8Unorm]
+        };
 
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label,
@@ -51,7 +59,7 @@ impl Texture {
             dimension: wgpu::TextureDimension::D2,
             format,
             usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-            view_formats: &[],
+            view_formats,
         });
 
         queue.write_texture(
@@ -129,6 +137,22 @@ impl Texture {
         texture
     }
 
+    pub fn create_depth_texture(
+        device: &wgpu::Device,
+        width: u32,
+        height: u32,
+        label: Option<&str>,
+    ) -> Self {
+        Self::create_2d_texture(
+            device,
+            width,
+            height,
+            Self::DEPTH_FORMAT,
+            wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            label,
+        )
+    }
+
     pub fn create_2d_texture(
         device: &wgpu::Device,
         width: u32,
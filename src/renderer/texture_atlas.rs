@@ -2,6 +2,8 @@ use super::{resources::load_texture, texture};
 
 pub struct TextureAtlas {
     pub texture: texture::Texture,
+    // Same texels as `texture`, but without srgb decoding. Used to read palette indices.
+    pub indexed_view: wgpu::TextureView,
     pub width: u32,
     pub height: u32,
 }
@@ -13,9 +15,15 @@ impl TextureAtlas {
         queue: &wgpu::Queue,
     ) -> anyhow::Result<Self> {
         let texture = load_texture(path, false, device, queue).await?;
+        let indexed_view = texture.texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("texture atlas indexed view"),
+            format: Some(wgpu::TextureFormat::Rgba8Unorm),
+            ..Default::default()
+        });
         Ok(Self {
             width: texture.size.width,
             height: texture.size.height,
+            indexed_view,
             texture,
         })
     }
@@ -19,8 +19,26 @@ struct InstanceInput {
 	@location(2) size: vec2<f32>,
 	@location(3) texture_origin: vec2<f32>,
 	@location(4) translation: vec2<f32>,
+	@location(5) scale: vec2<f32>,
+	@location(6) pivot: vec2<f32>,
+	@location(7) rotation: f32,
+	@location(8) layer: f32,
+	@location(9) tint: vec4<f32>,
+	@location(10) palette: u32,
+	@location(11) flags: u32,
 }
 
+const FLIP_X: u32 = 1u;
+const FLIP_Y: u32 = 2u;
+const UNLIT: u32 = 4u;
+// Animation index in bits 8..16 of the flags, phase in bits 16..24
+const ANIMATED: u32 = 8u;
+const MAX_LAYER: f32 = 32.0;
+// Texels a pipeline keeps, ALPHA_TEST is appended per pipeline by SpriteNode
+const ALPHA_TEST_NONE: u32 = 0u;
+const ALPHA_TEST_OPAQUE: u32 = 1u;
+const ALPHA_TEST_TRANSLUCENT: u32 = 2u;
+
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
@@ -28,21 +46,49 @@ struct VertexOutput {
 	@location(1) size: vec2<f32>,
 	@location(2) texture_origin: vec2<f32>,
 	@location(3) world_position: vec2<f32>,
+	@location(4) @interpolate(flat) palette: u32,
+	@location(5) tint: vec4<f32>,
+	@location(6) @interpolate(flat) flags: u32,
 }
 
-@vertex
-fn vs_main(input: VertexInput, ins: InstanceInput) -> VertexOutput {
+// Shared by the vertex entry points for the full and the packed instance layouts
+fn sprite_vertex(input: VertexInput, ins: InstanceInput) -> VertexOutput {
 	var out: VertexOutput;
-	// TODO: should be a part of the instance buffer
-	let scale_matrix = mat2x2<f32>(vec2<f32>(48.0, 0.0), vec2<f32>(0.0, 48.0));
-	let world_position = ((input.position + 0.5) * scale_matrix) + ins.translation;
+	// quad in world units with the origin in the bottom left corner
+	let quad_size = ins.size * ins.scale;
+	let local_position = (input.position + 0.5) * quad_size;
+	let pivot = ins.pivot * quad_size;
+	let c = cos(ins.rotation);
+	let s = sin(ins.rotation);
+	let rotation = mat2x2<f32>(vec2<f32>(c, s), vec2<f32>(-s, c));
+	let world_position = (rotation * (local_position - pivot)) + pivot + ins.translation;
 	let world_position_homogenous = vec4(world_position, 0.0, 1.0);
-	let position = camera.view_proj * world_position_homogenous;
+	var position = camera.view_proj * world_position_homogenous;
+
+	// Depth from the layer, and within a layer from how far down the screen the sprite
+	// is placed so lower sprites end up in front. Every vertex of a quad uses the same depth.
+	// Sprites anchored up to a view height past the top or bottom edge still sort, so the
+	// ones hanging into the view don't all share a key.
+	let base = camera.view_proj * vec4(ins.translation, 0.0, 1.0);
+	let y = clamp(((base.y / base.w + 1.0) * 0.5 + 1.0) / 3.0, 0.0, 1.0);
+	let sort_key = clamp(ins.layer, 0.0, MAX_LAYER) + (1.0 - y) * 0.99;
+	position.z = (1.0 - (sort_key + 1.0) / (MAX_LAYER + 2.0)) * position.w;
 	out.clip_position = position;
 	out.world_position = world_position;
-	out.tex_coords = input.tex_coords;
+	// mirror by flipping the texture coordinates, keeping the winding intact for culling
+	var tex_coords = input.tex_coords;
+	if ((ins.flags & FLIP_X) != 0u) {
+		tex_coords.x = 1.0 - tex_coords.x;
+	}
+	if ((ins.flags & FLIP_Y) != 0u) {
+		tex_coords.y = 1.0 - tex_coords.y;
+	}
+	out.tex_coords = tex_coords;
 	out.size = ins.size;
 	out.texture_origin = ins.texture_origin;
+	out.palette = ins.palette;
+	out.tint = ins.tint;
+	out.flags = ins.flags;
     return out;
 }
 
@@ -53,91 +99,97 @@ var texture: texture_2d<f32>;
 @group(0) @binding(1)
 var texture_sampler: sampler;
 
+// The atlas without srgb decoding, red channel holds the palette index of indexed sprites
 @group(0) @binding(2)
-var sdf_texture: texture_2d<f32>;
+var indexed_texture: texture_2d<f32>;
 
-@group(1) @binding(0)
-var<uniform> atlas: TextureAtlasUniform;
+@group(0) @binding(3)
+var palette_texture: texture_2d<f32>;
 
-@group(3) @binding(0)
-var<storage, read> lights: array<Light>;
+const NO_PALETTE: u32 = 0xFFFFFFFFu;
 
-struct Light {
-	position: vec2<f32>,
-	intensity: f32,
-	falloff: f32,
-	color: vec3<f32>,
-};
+@group(1) @binding(0)
+var<uniform> atlas: TextureAtlasUniform;
 
 
-//TODO: uniform
-const screen = vec2(1920., 1200.);
+// Written by scene sprites, see DeferredLightingNode
+struct GBufferOutput {
+	@location(0) albedo: vec4<f32>,
+	@location(1) emissive: vec4<f32>,
+}
 
-@fragment
-fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+// Colour of the sprite before lighting, with straight alpha
+fn sprite_color(in: VertexOutput) -> vec4<f32> {
 	// sprite
 	let sprite_size = in.size / atlas.size;
 	let uvOffset = in.tex_coords * sprite_size;
-	let localUv = uvOffset + (in.texture_origin / atlas.size);
+	var texture_origin = in.texture_origin;
+	if ((in.flags & ANIMATED) != 0u) {
+		texture_origin = tile_animation_origin((in.flags >> 8u) & 0xFFu, (in.flags >> 16u) & 0xFFu);
+	}
+	let localUv = uvOffset + (texture_origin / atlas.size);
 	
 	var base_sample = textureSample(texture, texture_sampler, localUv);
 
-	// lighting
-	// TODO: Should probably be a post processing step
-	let w_p = in.world_position;
-
-	// where to sample the sdf (world space)
-	let world_uv = w_p / screen;
-
-	// make everything dark
-	let ambient_light = vec3(0.015, 0.015, 0.015);
-	var final_color = base_sample.rgb * ambient_light;
-
-	// TODO: add num lights as a uniform
-	for (var i: i32 = 0; i < 10; i = i + 1) {
-		let light = lights[i];
-
-	 	let light_dir = normalize(light.position - w_p);
-		let dist = length(light.position - w_p);
-		var dist_traveled = 0.0;
-		var reached = true;
-
-		// soft shadows
-		var s = 1.0;
-		var k = 12.0;
-
-		// Raymarch a light.
-		for (var j: i32 = 0; j < 100; j = j + 1) {
-			// march from fragment to wards light. sample sdf to determine step distance
-			let d = textureSample(sdf_texture, texture_sampler, (w_p + (dist_traveled * light_dir)) / screen).r;
-
-			// hit a wall
-			if (d < 0.00001) {
-				reached = false;
-				break;
-			}
-
-			dist_traveled += d;
-
-			// calculate soft shadows
-			s = min(s, (k * d) / dist_traveled);
-
-			// overshot the light source
-			if (dist_traveled >= dist) {
-				break;
-			};
-		}
+	// indexed sprites look their colour up in a palette row, alpha stays from the atlas
+	if (in.palette != NO_PALETTE) {
+		let texel = vec2<i32>(localUv * atlas.size);
+		let index = u32(round(textureLoad(indexed_texture, texel, 0).r * 255.));
+		// out of range loads are undefined, stay inside the palette texture
+		let palette_size = vec2<u32>(textureDimensions(palette_texture));
+		let column = min(index, palette_size.x - 1u);
+		let row = min(in.palette, palette_size.y - 1u);
+		let color = textureLoad(palette_texture, vec2<i32>(i32(column), i32(row)), 0);
+		base_sample = vec4(color.rgb, base_sample.a);
+	}
+	base_sample *= in.tint;
+
+	// keep fully transparent texels out of the depth buffer
+	if (base_sample.a <= 0.0) {
+		discard;
+	}
+	// alpha blended sprites are drawn in an opaque and a translucent pass, see SpritePass
+	if ((ALPHA_TEST == ALPHA_TEST_OPAQUE && base_sample.a < 1.0) || (ALPHA_TEST == ALPHA_TEST_TRANSLUCENT && base_sample.a >= 1.0)) {
+		discard;
+	}
+
+	return base_sample;
+}
 
-		if (reached) {
-			// The constant part of the denominator diffuses the glow close to the light
-			// should add to uniform
-			let falloff = (light.intensity * 100.) / (40. + (dist * dist * light.falloff));
+// Lit sprites go into the albedo target for the lighting pass, unlit ones into the emissive
+// target. Both targets get the coverage, so what's behind is hidden in either.
+@fragment
+fn fs_main(in: VertexOutput) -> GBufferOutput {
+	let color = sprite_color(in);
+	// premultiplied alpha, see BlendMode
+	let premultiplied = vec4(color.rgb * color.a, color.a);
+	let coverage = vec4(0.0, 0.0, 0.0, color.a);
+
+	var out: GBufferOutput;
+	if ((in.flags & UNLIT) == 0u) {
+		out.albedo = premultiplied;
+		out.emissive = coverage;
+	} else {
+		out.albedo = coverage;
+		out.emissive = premultiplied;
+	}
+	return out;
+}
 
-			// Multiply in the base color to make sure we actually "light up" a tile instead
-			// of just diffusing the color
-			final_color += (base_sample.rgb * light.color) * falloff * s;
-		}
-	}	
+// Multiplied sprites scale both targets alike, so they tint lit and unlit colour the same
+// and aren't lit themselves
+@fragment
+fn fs_multiply(in: VertexOutput) -> GBufferOutput {
+	let color = sprite_color(in);
+	var out: GBufferOutput;
+	out.albedo = vec4(color.rgb * color.a, color.a);
+	out.emissive = out.albedo;
+	return out;
+}
 
-	return vec4(final_color, base_sample.a);
+// Overlay sprites are drawn unlit, straight into the overlay texture
+@fragment
+fn fs_overlay(in: VertexOutput) -> @location(0) vec4<f32> {
+	let color = sprite_color(in);
+	return vec4(color.rgb * color.a, color.a);
 }
@@ -0,0 +1,152 @@
+use anyhow::bail;
+
+/// Frames an animated tile can cycle through.
+pub const MAX_TILE_FRAMES: usize = 8;
+/// Animations that can be registered at once.
+pub const MAX_TILE_ANIMATIONS: usize = 64;
+
+/// Atlas frames shared by every tile of one kind, each shown for `frame_duration` seconds.
+#[derive(Debug, Clone)]
+pub struct TileAnimation {
+    frames: Vec<[f32; 2]>,
+    frame_duration: f32,
+}
+
+impl TileAnimation {
+    /// `frames` are atlas origins in pixels, all the same size as the tile.
+    pub fn new(frames: &[[f32; 2]], frame_duration: f32) -> Self {
+        Self {
+            frames: frames.to_vec(),
+            frame_duration,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct GpuTileAnimation {
+    frames: [[f32; 2]; MAX_TILE_FRAMES],
+    frame_count: u32,
+    frame_duration: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct TimeUniform {
+    // Seconds
+    time: f32,
+    _padding: [f32; 3],
+}
+
+/// Animations for map tiles, played on the GPU from a shared clock so tiles don't need to be
+/// rewritten when their frame changes. Shaders using it are concatenated with
+/// `tile_animation.wgsl` and bind it as bindings 1 and 2 of group 1.
+pub struct TileAnimations {
+    animations_buffer: wgpu::Buffer,
+    time_buffer: wgpu::Buffer,
+}
+
+impl TileAnimations {
+    pub const SHADER: &'static str = include_str!("tile_animation.wgsl");
+
+    pub fn new(device: &wgpu::Device) -> Self {
+        let animations_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Tile Animations Buffer"),
+            size: (MAX_TILE_ANIMATIONS * std::mem::size_of::<GpuTileAnimation>()) as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let time_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Tile Animation Time Buffer"),
+            size: std::mem::size_of::<TimeUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Self {
+            animations_buffer,
+            time_buffer,
+        }
+    }
+
+    /// Replace every animation, tiles refer to them by their index in `animations`.
+    pub fn write(&self, animations: &[TileAnimation], queue: &wgpu::Queue) -> anyhow::Result<()> {
+        if animations.len() > MAX_TILE_ANIMATIONS {
+            bail!(
+                "{} tile animations exceed the limit of {MAX_TILE_ANIMATIONS}",
+                animations.len()
+            );
+        }
+        let mut gpu_animations = Vec::with_capacity(animations.len());
+        for animation in animations {
+            let frame_count = animation.frames.len();
+            if frame_count == 0 || frame_count > MAX_TILE_FRAMES {
+                bail!("tile animations need 1 to {MAX_TILE_FRAMES} frames, got {frame_count}");
+            }
+            if animation.frame_duration <= 0. {
+                bail!("tile animation frame duration must be positive");
+            }
+            let mut frames = [[0.; 2]; MAX_TILE_FRAMES];
+            frames[..frame_count].copy_from_slice(&animation.frames);
+            gpu_animations.push(GpuTileAnimation {
+                frames,
+                frame_count: frame_count as u32,
+                frame_duration: animation.frame_duration,
+            });
+        }
+        queue.write_buffer(
+            &self.animations_buffer,
+            0,
+            bytemuck::cast_slice(&gpu_animations),
+        );
+
+        Ok(())
+    }
+
+    /// Set the clock every animation is played from, in seconds.
+    pub fn set_time(&self, time: f32, queue: &wgpu::Queue) {
+        let uniform = TimeUniform {
+            time,
+            _padding: [0.; 3],
+        };
+        queue.write_buffer(&self.time_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    pub fn bind_group_layout_entries() -> [wgpu::BindGroupLayoutEntry; 2] {
+        [
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Storage { read_only: true },
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ]
+    }
+
+    pub fn bind_group_entries(&self) -> [wgpu::BindGroupEntry<'_>; 2] {
+        [
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: self.animations_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: self.time_buffer.as_entire_binding(),
+            },
+        ]
+    }
+}
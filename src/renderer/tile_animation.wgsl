@@ -0,0 +1,28 @@
+// Must match MAX_TILE_FRAMES in tile_animation.rs
+const MAX_TILE_FRAMES: u32 = 8u;
+
+struct TileAnimation {
+	// atlas origins in pixels, only the first frame_count are used
+	frames: array<vec2<f32>, MAX_TILE_FRAMES>,
+	frame_count: u32,
+	frame_duration: f32,
+}
+
+struct AnimationTime {
+	// seconds
+	time: f32,
+}
+
+@group(1) @binding(1)
+var<storage, read> tile_animations: array<TileAnimation>;
+
+@group(1) @binding(2)
+var<uniform> animation_time: AnimationTime;
+
+// Atlas origin of the frame an animation shows right now. Every tile playing it is in sync,
+// phase shifts a tile by whole frames.
+fn tile_animation_origin(animation: u32, phase: u32) -> vec2<f32> {
+	let frame_count = tile_animations[animation].frame_count;
+	let frame = (u32(animation_time.time / tile_animations[animation].frame_duration) + phase) % frame_count;
+	return tile_animations[animation].frames[frame];
+}
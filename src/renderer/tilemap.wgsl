@@ -0,0 +1,105 @@
+struct TilemapUniform {
+	atlas_size: vec2<f32>,
+	// in tiles
+	map_size: vec2<u32>,
+	// size of a tile in world units
+	tile_size: f32,
+	// size of a tile in the atlas, in pixels
+	sprite_size: f32,
+	layer: f32,
+}
+
+struct CameraUniform {
+    view_proj: mat4x4<f32>
+};
+
+@group(1) @binding(0)
+var<uniform> tilemap: TilemapUniform;
+
+@group(2) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct VertexInput {
+    @location(0) position: vec2<f32>,
+	@location(1) tex_coords: vec2<f32>,
+}
+
+struct ChunkInput {
+	// bottom left corner of the chunk in world units
+	@location(2) origin: vec2<f32>,
+	// size of the chunk in world units
+	@location(3) size: vec2<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+	@location(0) world_position: vec2<f32>,
+}
+
+// Must match SpriteInstance::MAX_LAYER, see the sprite shader for how depth is derived
+const MAX_LAYER: f32 = 32.0;
+const EMPTY_TILE: u32 = 0xFFFFFFFFu;
+// Animation index in the low 8 bits, phase in bits 16..24
+const ANIMATED_TILE: u32 = 0x80000000u;
+
+@vertex
+fn vs_main(input: VertexInput, chunk: ChunkInput) -> VertexOutput {
+	var out: VertexOutput;
+	let world_position = (input.position + 0.5) * chunk.size + chunk.origin;
+	var position = camera.view_proj * vec4(world_position, 0.0, 1.0);
+	// a whole chunk sorts like the top of its layer, behind every sprite on the same layer
+	let sort_key = clamp(tilemap.layer, 0.0, MAX_LAYER);
+	position.z = (1.0 - (sort_key + 1.0) / (MAX_LAYER + 2.0)) * position.w;
+	out.clip_position = position;
+	out.world_position = world_position;
+    return out;
+}
+
+@group(0) @binding(0)
+var texture: texture_2d<f32>;
+
+// Atlas cell of every tile, x in the low 16 bits and y in the high 16 bits, or an animated
+// tile
+@group(0) @binding(1)
+var tile_indices: texture_2d<u32>;
+
+// Tiles are always lit, see DeferredLightingNode
+struct GBufferOutput {
+	@location(0) albedo: vec4<f32>,
+	@location(1) emissive: vec4<f32>,
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> GBufferOutput {
+	// tile (x, y) covers x..x + 1 tile_size wide, anchored at its bottom left like sprites
+	let tile_position = in.world_position / tilemap.tile_size;
+	let tile = vec2<i32>(floor(tile_position));
+	if (any(tile < vec2<i32>(0)) || any(tile >= vec2<i32>(tilemap.map_size))) {
+		discard;
+	}
+
+	let index = textureLoad(tile_indices, tile, 0).r;
+	if (index == EMPTY_TILE) {
+		discard;
+	}
+	var cell = vec2<f32>(f32(index & 0xFFFFu), f32(index >> 16u));
+	if ((index & ANIMATED_TILE) != 0u) {
+		cell = tile_animation_origin(index & 0xFFu, (index >> 16u) & 0xFFu) / tilemap.sprite_size;
+	}
+
+	// tiles go bottom up in the world, but top down in the atlas
+	var local = fract(tile_position);
+	local.y = 1.0 - local.y;
+	let texel = vec2<i32>(cell * tilemap.sprite_size + min(floor(local * tilemap.sprite_size), vec2(tilemap.sprite_size - 1.0)));
+	let base_sample = textureLoad(texture, texel, 0);
+
+	if (base_sample.a <= 0.0) {
+		discard;
+	}
+
+	// premultiplied alpha, like the sprite shader
+	var out: GBufferOutput;
+	out.albedo = vec4(base_sample.rgb * base_sample.a, base_sample.a);
+	out.emissive = vec4(0.0, 0.0, 0.0, base_sample.a);
+	return out;
+}
@@ -0,0 +1,352 @@
+use wgpu::util::DeviceExt;
+
+use super::{
+    camera::Camera, deferred_lighting::DeferredLightingNode,
+    pipeline_utils::create_multi_target_pipeline, texture_atlas::TextureAtlas,
+    tile_animation::TileAnimations, Texture,
+};
+
+/// Tiles in a chunk along each axis. Every chunk is drawn as a single quad.
+const CHUNK_TILES: u32 = 16;
+
+/// Renders a static layer of tiles from a texture of tile indices.
+///
+/// Instead of a quad per tile, every chunk of the map is one quad and the fragment shader
+/// looks up which atlas cell to sample per pixel. Writes the same G-buffer as the sprite path.
+pub struct TilemapNode {
+    pipeline: wgpu::RenderPipeline,
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    chunk_buffer: wgpu::Buffer,
+    chunks: u32,
+    texture_bind_group: wgpu::BindGroup,
+    tilemap_bind_group: wgpu::BindGroup,
+}
+
+impl TilemapNode {
+    /// Marks a tile that has nothing to draw.
+    pub const EMPTY_TILE: u32 = u32::MAX;
+
+    /// Set on tiles that play a tile animation rather than showing a single cell.
+    const ANIMATED_TILE: u32 = 1 << 31;
+
+    /// Index of the atlas cell at column `x`, row `y`.
+    pub fn tile_index(x: u32, y: u32) -> u32 {
+        (x & 0xFFFF) | (y << 16)
+    }
+
+    /// Index of a tile playing a tile animation registered with the renderer, `phase` shifts
+    /// it by whole frames.
+    pub fn animated_tile(animation: u32, phase: u32) -> u32 {
+        Self::ANIMATED_TILE | (animation & 0xFF) | (phase & 0xFF) << 16
+    }
+
+    /// `tiles` holds a tile index per tile, row by row from the bottom of the map.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        texture_atlas: &TextureAtlas,
+        tile_animations: &TileAnimations,
+        tiles: &[u32],
+        width: u32,
+        height: u32,
+        tile_size: f32,
+        sprite_size: f32,
+        layer: f32,
+    ) -> Self {
+        let tile_texture = Texture::create_2d_texture(
+            device,
+            width,
+            height,
+            wgpu::TextureFormat::R32Uint,
+            wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            Some("tilemap index texture"),
+        );
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &tile_texture.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            bytemuck::cast_slice(tiles),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * width),
+                rows_per_image: Some(height),
+            },
+            tile_texture.size,
+        );
+
+        // Layouts
+        let texture_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("tilemap texture bind group layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Uint,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+
+        let [animations_entry, time_entry] = TileAnimations::bind_group_layout_entries();
+        let tilemap_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("tilemap uniform bind group layout"),
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    animations_entry,
+                    time_entry,
+                ],
+            });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("tilemap pipeline layout"),
+            bind_group_layouts: &[
+                &texture_bind_group_layout,
+                &tilemap_bind_group_layout,
+                &Camera::create_bind_group_layout(device),
+            ],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = create_multi_target_pipeline(
+            device,
+            &pipeline_layout,
+            &DeferredLightingNode::color_targets(Some(
+                wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+            )),
+            "fs_main",
+            Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            &[Vertex::desc(), Chunk::desc()],
+            wgpu::PrimitiveTopology::TriangleList,
+            wgpu::ShaderModuleDescriptor {
+                label: Some("tilemap shader"),
+                source: wgpu::ShaderSource::Wgsl(
+                    [TileAnimations::SHADER, include_str!("tilemap.wgsl")]
+                        .concat()
+                        .into(),
+                ),
+            },
+            Some("tilemap pipeline"),
+        );
+
+        // Buffers
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("tilemap vertex buffer"),
+            contents: bytemuck::cast_slice(VERTICES),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("tilemap index buffer"),
+            contents: bytemuck::cast_slice(INDICES),
+            usage: wgpu::BufferUsages::INDEX,
+        });
+
+        // Only chunks with something in them get a quad. Tiles are anchored at their bottom
+        // left corner like sprites, so the grid starts at the origin.
+        let chunk_size = CHUNK_TILES as f32 * tile_size;
+        let mut chunks = Vec::new();
+        for chunk_y in 0..height.div_ceil(CHUNK_TILES) {
+            for chunk_x in 0..width.div_ceil(CHUNK_TILES) {
+                let occupied = (chunk_y * CHUNK_TILES..((chunk_y + 1) * CHUNK_TILES).min(height))
+                    .any(|y| {
+                        (chunk_x * CHUNK_TILES..((chunk_x + 1) * CHUNK_TILES).min(width))
+                            .any(|x| tiles[(y * width + x) as usize] != Self::EMPTY_TILE)
+                    });
+                if occupied {
+                    chunks.push(Chunk {
+                        origin: [chunk_x as f32 * chunk_size, chunk_y as f32 * chunk_size],
+                        size: [chunk_size, chunk_size],
+                    });
+                }
+            }
+        }
+
+        let chunk_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("tilemap chunk buffer"),
+            contents: bytemuck::cast_slice(&chunks),
+            usage: wgpu::BufferUsages::VERTEX,
+        });
+
+        let tilemap_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("tilemap buffer"),
+            contents: bytemuck::cast_slice(&[TilemapUniform {
+                atlas_size: [texture_atlas.width as f32, texture_atlas.height as f32],
+                map_size: [width, height],
+                tile_size,
+                sprite_size,
+                layer,
+                _padding: 0.,
+            }]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        // Bind groups
+        let texture_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("tilemap texture bind group"),
+            layout: &texture_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture_atlas.texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&tile_texture.view),
+                },
+            ],
+        });
+
+        let [animations_entry, time_entry] = tile_animations.bind_group_entries();
+        let tilemap_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("tilemap uniform bind group"),
+            layout: &tilemap_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: tilemap_buffer.as_entire_binding(),
+                },
+                animations_entry,
+                time_entry,
+            ],
+        });
+
+        Self {
+            pipeline,
+            vertex_buffer,
+            index_buffer,
+            chunk_buffer,
+            chunks: chunks.len() as u32,
+            texture_bind_group,
+            tilemap_bind_group,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct TilemapUniform {
+    atlas_size: [f32; 2],
+    map_size: [u32; 2],
+    tile_size: f32,
+    sprite_size: f32,
+    layer: f32,
+    _padding: f32,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct Chunk {
+    origin: [f32; 2],
+    size: [f32; 2],
+}
+
+impl Chunk {
+    const ATTRIBS: [wgpu::VertexAttribute; 2] =
+        wgpu::vertex_attr_array![2 => Float32x2, 3 => Float32x2];
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Chunk>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct Vertex {
+    position: [f32; 2],
+    tex_coords: [f32; 2],
+}
+
+impl Vertex {
+    const ATTRIBS: [wgpu::VertexAttribute; 2] =
+        wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x2];
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+const VERTICES: &[Vertex] = &[
+    Vertex {
+        position: [-0.5, 0.5],
+        tex_coords: [0.0, 0.0],
+    }, // Top-left
+    Vertex {
+        position: [0.5, 0.5],
+        tex_coords: [1.0, 0.0],
+    }, // Top-right
+    Vertex {
+        position: [-0.5, -0.5],
+        tex_coords: [0.0, 1.0],
+    }, // Bottom-left
+    Vertex {
+        position: [0.5, -0.5],
+        tex_coords: [1.0, 1.0],
+    }, // Bottom-right
+];
+
+const INDICES: &[u16] = &[2, 1, 0u16, 2, 3, 1];
+
+pub(super) trait DrawTilemap<'a> {
+    fn draw_tilemap(&mut self, tilemap_renderer: &'a TilemapNode, camera: &'a Camera);
+}
+
+impl<'a, 'b> DrawTilemap<'b> for wgpu::RenderPass<'a>
+where
+    'b: 'a,
+{
+    fn draw_tilemap(&mut self, tilemap_renderer: &'b TilemapNode, camera: &'b Camera) {
+        self.set_pipeline(&tilemap_renderer.pipeline);
+        self.set_vertex_buffer(0, tilemap_renderer.vertex_buffer.slice(..));
+        self.set_vertex_buffer(1, tilemap_renderer.chunk_buffer.slice(..));
+        self.set_index_buffer(
+            tilemap_renderer.index_buffer.slice(..),
+            wgpu::IndexFormat::Uint16,
+        );
+        self.set_bind_group(0, &tilemap_renderer.texture_bind_group, &[]);
+        self.set_bind_group(1, &tilemap_renderer.tilemap_bind_group, &[]);
+        self.set_bind_group(2, camera.bind_group(), &[]);
+        self.draw_indexed(0..INDICES.len() as u32, 0, 0..tilemap_renderer.chunks);
+    }
+}
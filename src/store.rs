@@ -0,0 +1,251 @@
+use std::{fmt, hash::Hash, marker::PhantomData, ops::Range};
+
+/// Handle to a value in a `Store`.
+///
+/// Handles stay valid when other values are removed, and a handle to a removed value never
+/// resolves to a value inserted after it, since slots are versioned by a generation.
+pub struct Handle<T> {
+    index: u32,
+    generation: u32,
+    _value: PhantomData<fn() -> T>,
+}
+
+// Implemented by hand, deriving would require T to implement these as well
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Handle<T> {}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.index == other.index && self.generation == other.generation
+    }
+}
+
+impl<T> Eq for Handle<T> {}
+
+impl<T> Hash for Handle<T> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+        self.generation.hash(state);
+    }
+}
+
+impl<T> fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Handle({}v{})", self.index, self.generation)
+    }
+}
+
+struct Slot {
+    generation: u32,
+    // Position of the value in the dense array, None while the slot is free
+    value: Option<usize>,
+}
+
+/// Densely packed values addressed through generational handles.
+///
+/// Values live in one contiguous array so they can be uploaded to the GPU as is. Removing
+/// a value swaps the last value into its place, so the order of values is not stable.
+/// Freed slots are reused by later inserts.
+///
+/// Positions that changed since the last call to `take_dirty` are tracked, so only those
+/// have to be uploaded again.
+pub struct Store<T> {
+    values: Vec<T>,
+    // Slot index of the value at the same position in `values`
+    owners: Vec<u32>,
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+    dirty: Vec<usize>,
+}
+
+impl<T> Store<T> {
+    pub fn new() -> Self {
+        Self {
+            values: Vec::new(),
+            owners: Vec::new(),
+            slots: Vec::new(),
+            free: Vec::new(),
+            dirty: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, value: T) -> Handle<T> {
+        let position = self.values.len();
+        let index = match self.free.pop() {
+            Some(index) => {
+                self.slots[index as usize].value = Some(position);
+                index
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: Some(position),
+                });
+                (self.slots.len() - 1) as u32
+            }
+        };
+        self.values.push(value);
+        self.owners.push(index);
+        self.dirty.push(position);
+
+        Handle {
+            index,
+            generation: self.slots[index as usize].generation,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn remove(&mut self, handle: Handle<T>) -> Option<T> {
+        let position = self.position(handle)?;
+
+        let slot = &mut self.slots[handle.index as usize];
+        slot.value = None;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(handle.index);
+
+        let value = self.values.swap_remove(position);
+        self.owners.swap_remove(position);
+        // Point the slot of the value that was moved into the gap at its new position
+        if let Some(&moved) = self.owners.get(position) {
+            self.slots[moved as usize].value = Some(position);
+            self.dirty.push(position);
+        }
+
+        Some(value)
+    }
+
+    /// Position of the value in the dense array, as returned by `as_slice`.
+    pub fn position(&self, handle: Handle<T>) -> Option<usize> {
+        let slot = self.slots.get(handle.index as usize)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        slot.value
+    }
+
+    pub fn contains(&self, handle: Handle<T>) -> bool {
+        self.position(handle).is_some()
+    }
+
+    pub fn get(&self, handle: Handle<T>) -> Option<&T> {
+        let position = self.position(handle)?;
+        self.values.get(position)
+    }
+
+    /// Mutable access to a value, which marks it as dirty.
+    pub fn get_mut(&mut self, handle: Handle<T>) -> Option<&mut T> {
+        let position = self.position(handle)?;
+        self.dirty.push(position);
+        self.values.get_mut(position)
+    }
+
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty.clear();
+        self.dirty.extend(0..self.values.len());
+    }
+
+    /// Ranges of positions that changed since the last call, sorted and merged.
+    pub fn take_dirty(&mut self) -> Vec<Range<usize>> {
+        let mut dirty = std::mem::take(&mut self.dirty);
+        dirty.sort_unstable();
+        dirty.dedup();
+
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        // Positions past the end belong to values that have since been removed
+        for position in dirty.into_iter().filter(|&p| p < self.values.len()) {
+            match ranges.last_mut() {
+                Some(range) if range.end == position => range.end += 1,
+                _ => ranges.push(position..position + 1),
+            }
+        }
+
+        ranges
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.values
+    }
+}
+
+impl<T> Default for Store<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale_handle_is_rejected_after_reinsert() {
+        let mut store = Store::new();
+        let old = store.insert(1);
+        assert_eq!(store.remove(old), Some(1));
+
+        // The freed slot is reused, but under a new generation
+        let new = store.insert(2);
+        assert_eq!(new.index, old.index);
+        assert_ne!(new, old);
+
+        assert!(!store.contains(old));
+        assert_eq!(store.get(old), None);
+        assert_eq!(store.get_mut(old), None);
+        assert_eq!(store.remove(old), None);
+        assert_eq!(store.get(new), Some(&2));
+    }
+
+    #[test]
+    fn swap_remove_moves_last_value_into_gap() {
+        let mut store = Store::new();
+        let a = store.insert('a');
+        let b = store.insert('b');
+        let c = store.insert('c');
+
+        assert_eq!(store.remove(a), Some('a'));
+
+        assert_eq!(store.as_slice(), &['c', 'b']);
+        assert_eq!(store.position(c), Some(0));
+        assert_eq!(store.position(b), Some(1));
+        assert_eq!(store.get(c), Some(&'c'));
+
+        // Removing the last value moves nothing
+        assert_eq!(store.remove(b), Some('b'));
+        assert_eq!(store.as_slice(), &['c']);
+        assert_eq!(store.position(c), Some(0));
+    }
+
+    #[test]
+    fn dirty_positions_coalesce_into_ranges() {
+        let mut store = Store::new();
+        let handles: Vec<_> = (0..8).map(|i| store.insert(i)).collect();
+        assert_eq!(store.take_dirty(), vec![0..8]);
+        assert!(store.take_dirty().is_empty());
+
+        for &i in &[5, 1, 2, 5, 6] {
+            *store.get_mut(handles[i]).unwrap() += 10;
+        }
+        assert_eq!(store.take_dirty(), vec![1..3, 5..7]);
+
+        // The moved value is dirty, the position past the new end is dropped
+        store.remove(handles[3]);
+        store.remove(handles[7]);
+        assert_eq!(store.take_dirty(), vec![3..4]);
+
+        store.mark_all_dirty();
+        assert_eq!(store.take_dirty(), vec![0..6]);
+    }
+}
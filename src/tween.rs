@@ -0,0 +1,287 @@
+use std::f32::consts::PI;
+
+use instant::Duration;
+
+use crate::{
+    entity::SpriteHandle,
+    world::{LightHandle, World},
+};
+
+/// Curves that map linear progress in 0..=1 to eased progress.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Easing {
+    #[default]
+    Linear,
+    QuadIn,
+    QuadOut,
+    QuadInOut,
+    CubicIn,
+    CubicOut,
+    CubicInOut,
+    SineInOut,
+    /// Overshoots the end a little before settling
+    BackOut,
+    BounceOut,
+}
+
+impl Easing {
+    pub fn apply(self, t: f32) -> f32 {
+        let t = t.clamp(0., 1.);
+        match self {
+            Easing::Linear => t,
+            Easing::QuadIn => t * t,
+            Easing::QuadOut => 1. - (1. - t).powi(2),
+            Easing::QuadInOut if t < 0.5 => 2. * t * t,
+            Easing::QuadInOut => 1. - (-2. * t + 2.).powi(2) / 2.,
+            Easing::CubicIn => t.powi(3),
+            Easing::CubicOut => 1. - (1. - t).powi(3),
+            Easing::CubicInOut if t < 0.5 => 4. * t.powi(3),
+            Easing::CubicInOut => 1. - (-2. * t + 2.).powi(3) / 2.,
+            Easing::SineInOut => -((PI * t).cos() - 1.) / 2.,
+            Easing::BackOut => {
+                const OVERSHOOT: f32 = 1.70158;
+                1. + (OVERSHOOT + 1.) * (t - 1.).powi(3) + OVERSHOOT * (t - 1.).powi(2)
+            }
+            Easing::BounceOut => {
+                const N: f32 = 7.5625;
+                const D: f32 = 2.75;
+                if t < 1. / D {
+                    N * t * t
+                } else if t < 2. / D {
+                    let t = t - 1.5 / D;
+                    N * t * t + 0.75
+                } else if t < 2.5 / D {
+                    let t = t - 2.25 / D;
+                    N * t * t + 0.9375
+                } else {
+                    let t = t - 2.625 / D;
+                    N * t * t + 0.984375
+                }
+            }
+        }
+    }
+}
+
+/// A property of the world that can be tweened.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum TweenTarget {
+    /// [x, y] in world units
+    SpritePosition(SpriteHandle),
+    /// [r, g, b, a]
+    SpriteTint(SpriteHandle),
+    /// [x, y] in world units
+    LightPosition(LightHandle),
+    /// [r, g, b]
+    LightColor(LightHandle),
+    LightIntensity(LightHandle),
+    /// Radians, spot lights only
+    LightDirection(LightHandle),
+    /// [x, y] of the view center in world units
+    CameraPosition,
+    /// Render target pixels per world unit
+    CameraZoom,
+}
+
+/// Up to four components, targets with fewer ignore the rest.
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct TweenValue(pub [f32; 4]);
+
+impl TweenValue {
+    fn lerp(self, to: Self, t: f32) -> Self {
+        Self(std::array::from_fn(|i| {
+            self.0[i] + (to.0[i] - self.0[i]) * t
+        }))
+    }
+}
+
+impl From<f32> for TweenValue {
+    fn from(value: f32) -> Self {
+        Self([value, 0., 0., 0.])
+    }
+}
+
+impl From<[f32; 2]> for TweenValue {
+    fn from([x, y]: [f32; 2]) -> Self {
+        Self([x, y, 0., 0.])
+    }
+}
+
+impl From<[f32; 3]> for TweenValue {
+    fn from([x, y, z]: [f32; 3]) -> Self {
+        Self([x, y, z, 0.])
+    }
+}
+
+impl From<[f32; 4]> for TweenValue {
+    fn from(value: [f32; 4]) -> Self {
+        Self(value)
+    }
+}
+
+/// Moves a target from wherever it is when the tween starts to `to`.
+#[derive(Debug, Copy, Clone)]
+pub struct Tween {
+    target: TweenTarget,
+    to: TweenValue,
+    duration: Duration,
+    easing: Easing,
+}
+
+impl Tween {
+    pub fn new(target: TweenTarget, to: impl Into<TweenValue>, duration: Duration) -> Self {
+        Self {
+            target,
+            to: to.into(),
+            duration,
+            easing: Easing::default(),
+        }
+    }
+
+    pub fn with_easing(mut self, easing: Easing) -> Self {
+        self.easing = easing;
+        self
+    }
+}
+
+pub type TweenCallback = Box<dyn FnMut(&mut World)>;
+
+enum Step {
+    // Run side by side, the step is done when the longest one is
+    Tweens(Vec<Tween>),
+    Wait(Duration),
+    Call(TweenCallback),
+}
+
+/// Steps that are played one after another.
+pub struct Sequence {
+    steps: Vec<Step>,
+    looped: bool,
+}
+
+impl Sequence {
+    pub fn new() -> Self {
+        Self {
+            steps: Vec::new(),
+            looped: false,
+        }
+    }
+
+    /// Start `tween` once the previous step has finished.
+    pub fn then(mut self, tween: Tween) -> Self {
+        self.steps.push(Step::Tweens(vec![tween]));
+        self
+    }
+
+    /// Run `tween` alongside the previous step.
+    pub fn with(mut self, tween: Tween) -> Self {
+        match self.steps.last_mut() {
+            Some(Step::Tweens(tweens)) => tweens.push(tween),
+            _ => self.steps.push(Step::Tweens(vec![tween])),
+        }
+        self
+    }
+
+    pub fn wait(mut self, duration: Duration) -> Self {
+        self.steps.push(Step::Wait(duration));
+        self
+    }
+
+    /// Call `callback` once the previous step has finished.
+    pub fn then_call(mut self, callback: impl FnMut(&mut World) + 'static) -> Self {
+        self.steps.push(Step::Call(Box::new(callback)));
+        self
+    }
+
+    /// Start over from the first step after the last one, forever.
+    pub fn looped(mut self) -> Self {
+        self.looped = true;
+        self
+    }
+}
+
+impl Default for Sequence {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<Tween> for Sequence {
+    fn from(tween: Tween) -> Self {
+        Self::new().then(tween)
+    }
+}
+
+/// A [`Sequence`] that is being played.
+pub struct Playing {
+    sequence: Sequence,
+    step: usize,
+    // Time into the current step
+    elapsed: Duration,
+    // Values of the current step's targets when it started
+    from: Vec<TweenValue>,
+}
+
+impl Playing {
+    pub fn new(sequence: Sequence) -> Self {
+        Self {
+            sequence,
+            step: 0,
+            elapsed: Duration::ZERO,
+            from: Vec::new(),
+        }
+    }
+
+    /// Advance by `delta`, writing the tweened values into the world. Returns whether the
+    /// sequence has finished.
+    pub fn update(&mut self, delta: Duration, world: &mut World) -> bool {
+        let mut remaining = delta;
+        // A looped sequence that takes no time would never give up the frame
+        let mut steps_left = self.sequence.steps.len() * 2;
+        loop {
+            if self.step == self.sequence.steps.len() {
+                if !self.sequence.looped || steps_left == 0 {
+                    return !self.sequence.looped;
+                }
+                self.step = 0;
+            }
+            steps_left = steps_left.saturating_sub(1);
+
+            let entered = self.elapsed.is_zero() && self.from.is_empty();
+            self.elapsed += remaining;
+            let duration = match &mut self.sequence.steps[self.step] {
+                Step::Tweens(tweens) => {
+                    if entered {
+                        self.from = tweens
+                            .iter()
+                            .map(|tween| world.tween_value(tween.target).unwrap_or(tween.to))
+                            .collect();
+                    }
+                    for (tween, from) in tweens.iter().zip(&self.from) {
+                        let t = if tween.duration.is_zero() {
+                            1.
+                        } else {
+                            self.elapsed.as_secs_f32() / tween.duration.as_secs_f32()
+                        };
+                        let value = from.lerp(tween.to, tween.easing.apply(t));
+                        world.set_tween_value(tween.target, value);
+                    }
+                    tweens.iter().map(|tween| tween.duration).max()
+                }
+                Step::Wait(duration) => Some(*duration),
+                Step::Call(callback) => {
+                    callback(world);
+                    None
+                }
+            }
+            .unwrap_or_default();
+
+            if self.elapsed < duration {
+                return false;
+            }
+            remaining = self.elapsed - duration;
+            self.elapsed = Duration::ZERO;
+            self.from.clear();
+            self.step += 1;
+        }
+    }
+}
@@ -1,4 +1,7 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    f32::consts::{FRAC_PI_2, PI},
+};
 
 use instant::{Duration, Instant};
 use rand::Rng;
@@ -8,17 +11,77 @@ use winit::{
 };
 
 use crate::{
-    constants::{parse_map, Position, Translation, Types, MAP, SPRITE_SIZE, TILES, TILE_SIZE},
-    entity::Entity,
-    renderer::{Camera, Light, Renderer, SpriteInstance},
+    animation::{AnimationState, AnimationStateMachine, Clip, Playback},
+    constants::{
+        parse_map, MapLight, MapTile, Position, Translation, Types, ANIMATED_TILES, DECAL_LAYER,
+        FRAME_ORIGIN, FRAME_SIZE, GEM_ORIGIN, GROUND_LAYER, LIGHTS, MAP, OBJECT_LAYER, PALETTES,
+        SPARK_FRAMES, SPARK_ORIGIN, SPARK_SIZE, SPRITE_SIZE, TILES, TILE_SIZE,
+    },
+    entity::{Entity, SpriteHandle},
+    renderer::{
+        Align, BitmapFont, BlendMode, Camera, Emitter, Light, LightShape, NineSlice,
+        PackedSpriteInstance, Renderer, Resolution, SpriteBatch, SpriteBuffer, SpriteGrid,
+        SpriteInstance, Text, TileAnimation, TilemapNode, UpscaleFilter,
+    },
+    store::Handle,
+    store::Store,
+    tween::{Easing, Playing, Sequence, Tween, TweenTarget, TweenValue},
     utils::Incrementor,
 };
 
+// Size of a cell in the grid used to cull the tilemap, in world units
+const MAP_CELL_SIZE: f32 = (8 * TILE_SIZE) as f32;
+
+// Size of a font pixel on screen
+const HUD_SCALE: f32 = 2.;
+// Space between the window edge and the panel, and between the panel and the text, in font
+// pixels
+const HUD_MARGIN: f32 = 4.;
+const HUD_PADDING: f32 = 6.;
+
+// Scene size when drawing pixel perfect, every pixel is one sprite texel
+const VIRTUAL_RESOLUTION: Resolution = Resolution::Virtual {
+    width: 320,
+    height: 200,
+};
+
+// Time each frame of the player's walk cycle is shown
+const WALK_FRAME_DURATION: Duration = Duration::from_millis(120);
+// Fired by the walk cycle whenever a foot touches the ground
+const FOOTSTEP: &str = "footstep";
+
+// How far lights sway, in world units, and how much their intensity pulses
+const LIGHT_SWAY: f32 = 16.;
+const LIGHT_PULSE: f32 = 0.15;
+// Index in LIGHTS of the light that is dark until the map is up, then fades in
+const FADING_LIGHT: usize = 5;
+
+// Time for the security camera to turn from one side to the other
+const SECURITY_CAMERA_SWEEP: Duration = Duration::from_secs(3);
+
+pub type LightHandle = Handle<Light>;
+
+// Sprites added per frame while the stress test key is held
+const STRESS_SPRITES_PER_FRAME: usize = 1000;
+// The stress test stops adding sprites here, or earlier if the packed instance buffer can't
+// hold this many on the device
+const MAX_STRESS_SPRITES: usize = 1_000_000;
+
+// Where an upload failed, errors are reported once per site
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum Upload {
+    StressSprites,
+    Sprites,
+    Lights,
+    Overlay,
+}
+
 struct Input {
     up: bool,
     left: bool,
     right: bool,
     down: bool,
+    stress: bool,
 }
 pub struct World {
     window: winit::window::Window,
@@ -30,14 +93,32 @@ pub struct World {
     time_since_last_frame: Duration,
     time_tot: Duration,
     frames: i32,
+    // Frames in the last full second
+    fps: i32,
     acc_time: Duration,
-    sprite_instances: Vec<SpriteInstance>,
-    instance_map: HashMap<usize, usize>,
-    pub map: HashMap<(usize, usize), Position>,
+    sprite_instances: Store<SpriteInstance>,
+    // Sprites without an entity, spawned to stress test the renderer
+    stress_instances: Store<PackedSpriteInstance>,
+    // The tilemap never changes, so it lives in its own buffer that is only written once.
+    // Ground tiles come first, followed by everything on the object layer.
+    map_instances: Vec<SpriteInstance>,
+    ground_grid: SpriteGrid,
+    object_grid: SpriteGrid,
+    ground_instances: u32,
+    pub map: HashMap<(usize, usize), MapTile>,
+    // in tiles
+    map_size: (usize, usize),
+    // Draw the ground from the tile index texture instead of instancing a quad per tile
+    tilemap_ground: bool,
     pub entities: Vec<Entity>,
     input: Input,
     debug_texture: bool,
-    lights: Vec<Light>,
+    lights: Store<Light>,
+    tweens: Vec<Playing>,
+    font: BitmapFont,
+    overlay_instances: u32,
+    // Uploads that already printed an error, so ones that fail every frame only report once
+    reported_errors: HashSet<Upload>,
 }
 
 impl World {
@@ -48,106 +129,46 @@ impl World {
         let acc_time = Duration::from_millis(0);
         let size = window.inner_size();
         let (map, occluder_data, width, height) = parse_map(MAP);
-        let renderer = Renderer::new(&window, occluder_data, width, height).await?;
-        let sprite_instances =
-            Vec::with_capacity(std::mem::size_of::<SpriteInstance>() * 1_000_000);
-        let instance_map = HashMap::new();
+        let renderer = Renderer::new(&window, occluder_data, width, height, &PALETTES).await?;
+        let font = BitmapFont::load("pixel_font.fnt").await?;
+        let sprite_instances = Store::new();
+        let map_size = (width as usize / TILE_SIZE, height as usize / TILE_SIZE);
+        let map_instances = Vec::new();
+        let ground_grid = SpriteGrid::build(&mut [], MAP_CELL_SIZE);
+        let object_grid = SpriteGrid::build(&mut [], MAP_CELL_SIZE);
         let entities = Vec::new();
-        let camera = Camera::new(
+        let mut camera = Camera::new(
             &renderer.device,
             size.height as f32,
             size.width as f32,
             1.0,
             (0., 0.),
         );
+        camera.set_pixel_snap(true);
         let id_generator = Incrementor::new();
 
-        let lights = Vec::from([
-            Light {
-                position: [1200., 920.],
-                intensity: 3.,
-                falloff: 0.4,
-                color: [1., 1., 1.],
-                frequency: 2.,
-            },
-            Light {
-                position: [300., 900.],
-                intensity: 3.,
-                falloff: 0.2,
-                color: [0.7, 0.3, 0.1],
-                frequency: 2.5,
-            },
-            Light {
-                position: [150., 500.],
-                intensity: 3.,
-                falloff: 0.2,
-                color: [0.4, 0.2, 0.8],
-                frequency: 4.,
-            },
-            Light {
-                position: [300., 200.],
-                intensity: 3.,
-                falloff: 0.4,
-                color: [0.3, 0.2, 0.8],
-                frequency: 1.,
-            },
-            Light {
-                position: [500., 550.],
-                intensity: 6.,
-                falloff: 0.2,
-                color: [0.98, 0.34, 0.13],
-                frequency: 2.,
-            },
-            Light {
-                position: [1000., 550.],
-                intensity: 0.,
-                falloff: 0.2,
-                color: [1., 0.5, 0.3],
-                frequency: 0.,
-            },
-            Light {
-                position: [1400., 550.],
-                intensity: 8.,
-                falloff: 0.2,
-                color: [0., 0.5, 0.3],
-                frequency: 3.,
-            },
-            Light {
-                position: [1800., 350.],
-                intensity: 2.,
-                falloff: 0.4,
-                color: [0.4, 0.8, 0.1],
-                frequency: 7.,
-            },
-            Light {
-                position: [1500., 150.],
-                intensity: 3.,
-                falloff: 0.4,
-                color: [0.7, 0.3, 0.1],
-                frequency: 1.,
-            },
-            Light {
-                position: [1800., 950.],
-                intensity: 3.,
-                falloff: 0.4,
-                color: [0.1, 1.0, 0.5],
-                frequency: 5.,
-            },
-        ]);
+        let lights = Store::new();
         Ok(Self {
             renderer,
-            instance_map,
             id_generator,
             size,
             map,
+            map_size,
             window,
             lights,
+            tweens: Vec::new(),
             camera,
             frames: 0,
+            fps: 0,
             time,
             time_tot,
             time_since_last_frame,
             sprite_instances,
+            stress_instances: Store::new(),
+            map_instances,
+            ground_grid,
+            object_grid,
+            ground_instances: 0,
             acc_time,
             entities,
             input: Input {
@@ -155,8 +176,13 @@ impl World {
                 left: false,
                 right: false,
                 down: false,
+                stress: false,
             },
             debug_texture: false,
+            tilemap_ground: true,
+            font,
+            overlay_instances: 0,
+            reported_errors: HashSet::new(),
         })
     }
 
@@ -165,10 +191,18 @@ impl World {
             self.size = size;
         }
         self.renderer.resize(self.size);
+        self.update_camera_projection(0.);
+    }
 
-        // TODO: move into renderer
+    // TODO: move into renderer
+    fn update_camera_projection(&mut self, d_s: f32) {
+        let size = self.renderer.target_size();
         self.camera
-            .update_view_projection(self.size.height as f32, self.size.width as f32, 0.);
+            .update_view_projection(size.height as f32, size.width as f32, d_s);
+        self.write_camera();
+    }
+
+    fn write_camera(&self) {
         self.renderer.queue.write_buffer(
             &self.camera.uniform.buffer,
             0,
@@ -181,9 +215,50 @@ impl World {
     }
 
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
+        // Only the parts of the map the camera sees are drawn
+        let bounds = self.camera.visible_bounds();
+        let mut ranges = Vec::new();
+        if !self.tilemap_ground {
+            ranges.extend(self.ground_grid.visible_ranges(&bounds));
+        }
+        ranges.extend(
+            self.object_grid
+                .visible_ranges(&bounds)
+                .into_iter()
+                .map(|range| {
+                    range.start + self.ground_instances..range.end + self.ground_instances
+                }),
+        );
+        let mut batches: Vec<SpriteBatch> = ranges
+            .into_iter()
+            .map(|range| SpriteBatch::new(SpriteBuffer::Static, range, BlendMode::Alpha))
+            .collect();
+        batches.push(SpriteBatch::new(
+            SpriteBuffer::Dynamic,
+            0..self.renderer.sprite_count(SpriteBuffer::Dynamic),
+            BlendMode::Alpha,
+        ));
+        batches.push(SpriteBatch::new(
+            SpriteBuffer::Overlay,
+            0..self.overlay_instances,
+            BlendMode::Alpha,
+        ));
+        batches.push(SpriteBatch::new(
+            SpriteBuffer::Particles,
+            0..self.renderer.particle_count(),
+            BlendMode::Additive,
+        ));
+        if !self.stress_instances.is_empty() {
+            batches.push(SpriteBatch::new(
+                SpriteBuffer::Packed,
+                0..self.renderer.sprite_count(SpriteBuffer::Packed),
+                BlendMode::Alpha,
+            ));
+        }
         self.renderer.render(
             &self.camera,
-            self.sprite_instances.len() as u32,
+            &batches,
+            self.tilemap_ground,
             self.debug_texture,
         )?;
         Ok(())
@@ -197,23 +272,22 @@ impl World {
         self.frames += 1;
         self.acc_time += self.time_since_last_frame;
         if self.acc_time >= Duration::from_millis(1000) {
-            println!("entities: {}", self.entities.len());
-            println!("FPS: {}", self.frames);
+            self.fps = self.frames;
             self.acc_time = Duration::from_millis(0);
             self.frames = 0;
         }
 
         if let Some(position) = self.move_player() {
-            // TODO: abstract this
             self.camera.move_camera(position);
-            self.renderer.queue.write_buffer(
-                &self.camera.uniform.buffer,
-                0,
-                bytemuck::cast_slice(&[self.camera.get_uniform().uniform]),
-            );
+            self.write_camera();
         };
 
-        self.move_lights();
+        self.update_tweens();
+        self.update_animations();
+        self.renderer
+            .set_animation_time(self.time_tot.as_secs_f32());
+        self.renderer
+            .update_particles(self.time_since_last_frame.as_secs_f32());
 
         // let mut rng = rand::thread_rng();
         // let mut rng_y = rand::thread_rng();
@@ -226,34 +300,123 @@ impl World {
         //                 y: rng_y.gen_range(0..=1200) as f32,
         //             },
         //         },
+        //         OBJECT_LAYER,
         //         Types::PLAYER,
         //     );
         // }
 
+        if self.input.stress {
+            self.spawn_stress_sprites();
+        }
+        // Spawning stops at the buffer's limit, so there is nothing to retry if this fails
+        let dirty = self.stress_instances.take_dirty();
+        if let Err(e) = self
+            .renderer
+            .draw_packed_sprites(self.stress_instances.as_slice(), &dirty)
+        {
+            self.report_error(Upload::StressSprites, e);
+        }
+
         //currently we just generate sdf for the whole map. This is put here
         //to support when I start generating sdfs based on what the camera sees instead
-        self.renderer.draw_sprites(
-            &self.sprite_instances,
-            // self.entities.len() as u64,
+        let dirty = self.sprite_instances.take_dirty();
+        if let Err(e) = self
+            .renderer
+            .draw_sprites(self.sprite_instances.as_slice(), &dirty)
+        {
+            self.report_error(Upload::Sprites, e);
+            // Nothing was uploaded, try again next frame
+            self.sprite_instances.mark_all_dirty();
+        }
+
+        let dirty = self.lights.take_dirty();
+        if let Err(e) = self.renderer.draw_lights(self.lights.as_slice(), &dirty) {
+            self.report_error(Upload::Lights, e);
+            // Nothing was uploaded, try again next frame
+            self.lights.mark_all_dirty();
+        }
+        self.draw_hud();
+    }
+
+    fn report_error(&mut self, upload: Upload, e: anyhow::Error) {
+        if self.reported_errors.insert(upload) {
+            eprintln!("{:?}", e);
+        }
+    }
+
+    // Frame rate and counters in the top right corner of the window
+    fn draw_hud(&mut self) {
+        let stats = format!(
+            "FPS: {}\nentities: {}\nstress sprites: {}",
+            self.fps,
+            self.entities.len(),
+            self.stress_instances.len()
+        );
+        let target = self.renderer.target_size();
+        // The virtual resolution is already magnified
+        let scale = match self.renderer.resolution() {
+            Resolution::Window => HUD_SCALE,
+            Resolution::Virtual { .. } => 1.,
+        };
+        let (margin, padding) = (HUD_MARGIN * scale, HUD_PADDING * scale);
+        let text = Text::new(&self.font, &stats)
+            .with_scale(scale)
+            .with_align(Align::Right)
+            .with_layer(SpriteInstance::MAX_LAYER)
+            .with_lighting(false);
+        let [width, height] = text.size();
+        let top = target.height as f32 - margin;
+        let left = target.width as f32 - margin - width - 2. * padding;
+
+        let mut sprites = NineSlice::new(
+            [FRAME_ORIGIN.x, FRAME_ORIGIN.y],
+            [FRAME_SIZE, FRAME_SIZE],
+            [SPRITE_SIZE; 4],
+        )
+        // Whole pixels, so the frame stays sharp at small scales
+        .with_pixel_scale((scale / 2.).ceil())
+        .with_layer(SpriteInstance::MAX_LAYER - 1.)
+        .with_lighting(false)
+        .instances(
+            [left, top - height - 2. * padding],
+            [width + 2. * padding, height + 2. * padding],
         );
+        sprites.extend(text.instances([left + padding, top - padding]));
 
-        self.renderer.draw_lights(&self.lights);
+        self.overlay_instances = sprites.len() as u32;
+        if let Err(e) = self.renderer.draw_overlay_sprites(&sprites) {
+            self.report_error(Upload::Overlay, e);
+            self.overlay_instances = 0;
+        }
+    }
+
+    fn toggle_virtual_resolution(&mut self) {
+        let resolution = match self.renderer.resolution() {
+            Resolution::Window => VIRTUAL_RESOLUTION,
+            Resolution::Virtual { .. } => Resolution::Window,
+        };
+        self.renderer.set_resolution(resolution);
+        // One sprite texel per pixel at the virtual resolution
+        self.camera.set_scale(match resolution {
+            Resolution::Window => 1.,
+            Resolution::Virtual { .. } => SPRITE_SIZE / TILE_SIZE as f32,
+        });
+        self.update_camera_projection(0.);
+    }
+
+    fn toggle_upscale_filter(&mut self) {
+        let filter = match self.renderer.upscale_filter() {
+            UpscaleFilter::Integer => UpscaleFilter::SharpBilinear,
+            UpscaleFilter::SharpBilinear => UpscaleFilter::Integer,
+        };
+        self.renderer.set_upscale_filter(filter);
     }
 
     pub fn input(&mut self, event: &WindowEvent) -> bool {
         match event {
             WindowEvent::TouchpadMagnify { delta, .. } => {
                 //TODO: fix this
-                self.camera.update_view_projection(
-                    self.size.height as f32,
-                    self.size.width as f32,
-                    *delta as f32 * 5.,
-                );
-                self.renderer.queue.write_buffer(
-                    &self.camera.uniform.buffer,
-                    0,
-                    bytemuck::cast_slice(&[self.camera.get_uniform().uniform]),
-                );
+                self.update_camera_projection(*delta as f32 * 5.);
                 true
             }
             WindowEvent::KeyboardInput {
@@ -272,6 +435,11 @@ impl World {
                         VirtualKeyCode::W => self.input.up = true,
                         VirtualKeyCode::S => self.input.down = true,
                         VirtualKeyCode::U => self.debug_texture = true,
+                        VirtualKeyCode::P => self.input.stress = true,
+                        VirtualKeyCode::C => self.clear_stress_sprites(),
+                        VirtualKeyCode::T => self.tilemap_ground = !self.tilemap_ground,
+                        VirtualKeyCode::V => self.toggle_virtual_resolution(),
+                        VirtualKeyCode::F => self.toggle_upscale_filter(),
                         _ => {}
                     },
                     ElementState::Released => match key {
@@ -280,6 +448,7 @@ impl World {
                         VirtualKeyCode::W => self.input.up = false,
                         VirtualKeyCode::S => self.input.down = false,
                         VirtualKeyCode::U => self.debug_texture = false,
+                        VirtualKeyCode::P => self.input.stress = false,
                         _ => {}
                     },
                 }
@@ -289,40 +458,339 @@ impl World {
         }
     }
 
-    fn spawn_sprite(&mut self, texture_origin: &Position, translation: Translation, kind: Types) {
+    fn spawn_sprite(
+        &mut self,
+        texture_origin: &Position,
+        translation: Translation,
+        layer: f32,
+        kind: Types,
+    ) -> SpriteHandle {
         let Some(id) = self.id_generator.next() else {
             panic!("could not generate id for entity");
         };
-        let sprite = Entity::new(id, kind);
-        let instance_id = self.sprite_instances.len();
-        self.sprite_instances.push(SpriteInstance::new(
+        let handle =
+            self.sprite_instances
+                .insert(Self::tile_instance(texture_origin, translation, layer));
+
+        self.entities.push(Entity::new(id, kind, handle));
+
+        handle
+    }
+
+    fn tile_instance(
+        texture_origin: &Position,
+        translation: Translation,
+        layer: f32,
+    ) -> SpriteInstance {
+        SpriteInstance::new(
             [SPRITE_SIZE, SPRITE_SIZE],
             [texture_origin.x, texture_origin.y],
             [translation.position.x, translation.position.y],
-        ));
+        )
+        .with_scale([TILE_SIZE as f32 / SPRITE_SIZE; 2])
+        .with_layer(layer)
+    }
+
+    // Scatter sprites over the map in the packed layout, which is what makes a million of
+    // them affordable to upload
+    fn spawn_stress_sprites(&mut self) {
+        let (width, height) = self.map_size;
+        let mut rng = rand::thread_rng();
+        let limit = MAX_STRESS_SPRITES.min(self.renderer.sprite_limit(SpriteBuffer::Packed));
+        let count = STRESS_SPRITES_PER_FRAME.min(limit.saturating_sub(self.stress_instances.len()));
+        for _ in 0..count {
+            let translation = Translation {
+                position: Position {
+                    x: rng.gen_range(0..width * TILE_SIZE) as f32,
+                    y: rng.gen_range(0..height * TILE_SIZE) as f32,
+                },
+            };
+            self.stress_instances.insert(
+                Self::tile_instance(&TILES.player_walk_down_2, translation, OBJECT_LAYER).into(),
+            );
+        }
+    }
 
-        self.instance_map.insert(id, instance_id);
+    // Drop the stress test sprites and give back the instance memory they grew the buffers to
+    fn clear_stress_sprites(&mut self) {
+        self.stress_instances = Store::new();
+        if let Err(e) = self.renderer.draw_packed_sprites(&[], &[]) {
+            eprintln!("{:?}", e);
+        }
+        self.renderer.shrink_sprite_buffers(0);
+    }
 
-        self.entities.push(sprite);
+    /// Drive the sprite's texture with `animation`. Returns false if the sprite has no entity.
+    pub fn animate(&mut self, handle: SpriteHandle, animation: AnimationStateMachine) -> bool {
+        let Some(entity) = self.entities.iter_mut().find(|e| e.sprite == handle) else {
+            return false;
+        };
+        if let Some(instance) = self.sprite_instances.get_mut(handle) {
+            animation.apply(instance);
+        }
+        entity.animation = Some(animation);
+
+        true
+    }
+
+    fn update_animations(&mut self) {
+        let delta = self.time_since_last_frame;
+        for entity in self.entities.iter_mut() {
+            let Some(animation) = entity.animation.as_mut() else {
+                continue;
+            };
+            let changed = animation.update(delta);
+            for event in animation.events() {
+                log::debug!("entity {}: {}", entity.id, event);
+            }
+            if changed {
+                if let Some(instance) = self.sprite_instances.get_mut(entity.sprite) {
+                    animation.apply(instance);
+                }
+            }
+        }
+    }
+
+    /// Start playing a tween or sequence. Tweens on the same target fight over it, the one
+    /// started last wins.
+    pub fn play(&mut self, sequence: impl Into<Sequence>) {
+        self.tweens.push(Playing::new(sequence.into()));
+    }
+
+    fn update_tweens(&mut self) {
+        let delta = self.time_since_last_frame;
+        // Callbacks get the world, so the sequences are taken out while they run. Anything
+        // they start is played from the next frame on.
+        let mut tweens = std::mem::take(&mut self.tweens);
+        tweens.retain_mut(|tween| !tween.update(delta, self));
+        tweens.append(&mut self.tweens);
+        self.tweens = tweens;
+    }
+
+    pub(crate) fn tween_value(&self, target: TweenTarget) -> Option<TweenValue> {
+        Some(match target {
+            TweenTarget::SpritePosition(handle) => {
+                let translation = self.sprite_instances.get(handle)?.translation;
+                [translation.x(), translation.y()].into()
+            }
+            TweenTarget::SpriteTint(handle) => self.sprite_instances.get(handle)?.tint.into(),
+            TweenTarget::LightPosition(handle) => self.lights.get(handle)?.position.into(),
+            TweenTarget::LightColor(handle) => self.lights.get(handle)?.color.into(),
+            TweenTarget::LightIntensity(handle) => self.lights.get(handle)?.intensity.into(),
+            TweenTarget::LightDirection(handle) => match self.lights.get(handle)?.shape {
+                LightShape::Spot { direction, .. } => direction.into(),
+                _ => return None,
+            },
+            TweenTarget::CameraPosition => {
+                let (x, y) = self.camera.offset();
+                [x, y].into()
+            }
+            TweenTarget::CameraZoom => self.camera.scale().into(),
+        })
+    }
+
+    pub(crate) fn set_tween_value(&mut self, target: TweenTarget, TweenValue(value): TweenValue) {
+        let [x, y, z, _] = value;
+        match target {
+            TweenTarget::SpritePosition(handle) => {
+                self.update_sprite(handle, |sprite| sprite.set_translation([x, y]));
+            }
+            TweenTarget::SpriteTint(handle) => {
+                self.update_sprite(handle, |sprite| sprite.tint = value);
+            }
+            TweenTarget::LightPosition(handle) => {
+                if let Some(light) = self.lights.get_mut(handle) {
+                    light.position = [x, y];
+                }
+            }
+            TweenTarget::LightColor(handle) => {
+                if let Some(light) = self.lights.get_mut(handle) {
+                    light.color = [x, y, z];
+                }
+            }
+            TweenTarget::LightIntensity(handle) => {
+                if let Some(light) = self.lights.get_mut(handle) {
+                    light.intensity = x;
+                }
+            }
+            TweenTarget::LightDirection(handle) => {
+                if let Some(Light {
+                    shape: LightShape::Spot { direction, .. },
+                    ..
+                }) = self.lights.get_mut(handle)
+                {
+                    *direction = x;
+                }
+            }
+            TweenTarget::CameraPosition => {
+                self.camera.move_camera((x, y));
+                self.write_camera();
+            }
+            TweenTarget::CameraZoom => {
+                self.camera.set_scale(x);
+                self.write_camera();
+            }
+        }
+    }
+
+    pub fn add_light(&mut self, light: Light) -> LightHandle {
+        self.lights.insert(light)
+    }
+
+    /// Remove a light. Returns false if it has already been removed.
+    pub fn remove_light(&mut self, handle: LightHandle) -> bool {
+        self.lights.remove(handle).is_some()
+    }
+
+    pub fn light(&self, handle: LightHandle) -> Option<&Light> {
+        self.lights.get(handle)
+    }
+
+    /// Remove the entity with the given id along with its sprite.
+    pub fn despawn(&mut self, id: usize) -> bool {
+        let Some(index) = self.entities.iter().position(|e| e.id == id) else {
+            return false;
+        };
+        let entity = self.entities.swap_remove(index);
+        self.sprite_instances.remove(entity.sprite);
+
+        true
+    }
+
+    pub fn sprite(&self, handle: SpriteHandle) -> Option<&SpriteInstance> {
+        self.sprite_instances.get(handle)
+    }
+
+    /// Modify a sprite in place. Returns false if the sprite has been despawned.
+    pub fn update_sprite(
+        &mut self,
+        handle: SpriteHandle,
+        update: impl FnOnce(&mut SpriteInstance),
+    ) -> bool {
+        match self.sprite_instances.get_mut(handle) {
+            Some(instance) => {
+                update(instance);
+                true
+            }
+            None => false,
+        }
     }
 
     pub(crate) fn initialize_map(&mut self) {
         //TODO: clean up and fix parsing
-        let map = self.map.clone();
-        for ((x, y), tile) in map {
-            self.spawn_sprite(
-                &tile,
+        let animations: Vec<TileAnimation> = ANIMATED_TILES
+            .iter()
+            .map(|tile| {
+                let frames: Vec<[f32; 2]> = tile.frames.iter().map(|f| [f.x, f.y]).collect();
+                TileAnimation::new(&frames, tile.frame_duration)
+            })
+            .collect();
+        if let Err(e) = self.renderer.write_tile_animations(&animations) {
+            eprintln!("{:?}", e);
+        }
+
+        let map_instances = |layer: f32| {
+            self.map
+                .iter()
+                .filter(move |(_, tile)| tile.layer == layer)
+                .map(|(&(x, y), tile)| {
+                    let instance = Self::tile_instance(
+                        &tile.texture_origin,
+                        Translation {
+                            position: Position {
+                                x: (x * TILE_SIZE) as f32,
+                                y: (y * TILE_SIZE) as f32,
+                            },
+                        },
+                        tile.layer,
+                    );
+                    match tile.animation {
+                        Some(animation) => instance.with_tile_animation(animation, 0),
+                        None => instance,
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut ground = map_instances(GROUND_LAYER);
+        let mut objects = map_instances(OBJECT_LAYER);
+        // A rug in the bottom room, stretched from a single frame in the atlas
+        objects.extend(
+            NineSlice::new(
+                [FRAME_ORIGIN.x, FRAME_ORIGIN.y],
+                [FRAME_SIZE, FRAME_SIZE],
+                [SPRITE_SIZE; 4],
+            )
+            .with_pixel_scale(TILE_SIZE as f32 / SPRITE_SIZE)
+            .with_layer(DECAL_LAYER)
+            .instances(
+                [(16 * TILE_SIZE) as f32, (2 * TILE_SIZE) as f32],
+                [(7 * TILE_SIZE) as f32, (3 * TILE_SIZE) as f32],
+            ),
+        );
+        // Gems on the rug, the same indexed art in every palette row
+        objects.extend((0..PALETTES.len()).map(|row| {
+            Self::tile_instance(
+                &GEM_ORIGIN,
                 Translation {
                     position: Position {
-                        x: (x * TILE_SIZE) as f32,
-                        y: (y * TILE_SIZE) as f32,
+                        x: ((18 + 2 * row) * TILE_SIZE) as f32,
+                        y: (3 * TILE_SIZE) as f32,
                     },
                 },
-                Types::ENVIRONMENT,
+                OBJECT_LAYER,
             )
+            .with_palette(row as u32)
+        }));
+        self.ground_grid = SpriteGrid::build(&mut ground, MAP_CELL_SIZE);
+        self.object_grid = SpriteGrid::build(&mut objects, MAP_CELL_SIZE);
+        self.ground_instances = ground.len() as u32;
+        self.map_instances = [ground, objects].concat();
+        if let Err(e) = self.renderer.write_static_sprites(&self.map_instances) {
+            eprintln!("{:?}", e);
         }
+
+        let (width, height) = self.map_size;
+        let mut tiles = vec![TilemapNode::EMPTY_TILE; width * height];
+        for (&(x, y), tile) in self.map.iter().filter(|(_, t)| t.layer == GROUND_LAYER) {
+            tiles[y * width + x] = match tile.animation {
+                Some(animation) => TilemapNode::animated_tile(animation, 0),
+                None => TilemapNode::tile_index(
+                    (tile.texture_origin.x / SPRITE_SIZE) as u32,
+                    (tile.texture_origin.y / SPRITE_SIZE) as u32,
+                ),
+            };
+        }
+        self.renderer.write_tilemap(
+            &tiles,
+            width as u32,
+            height as u32,
+            TILE_SIZE as f32,
+            SPRITE_SIZE,
+            GROUND_LAYER,
+        );
+
+        // Sparks rising from the orange light, glowing on their own
+        let sparks = Emitter::new(
+            [500., 550.],
+            60.,
+            [SPARK_ORIGIN.x, SPARK_ORIGIN.y],
+            [SPARK_SIZE, SPARK_SIZE],
+        )
+        .with_frames(SPARK_FRAMES)
+        .with_lifetime(0.6, 1.4)
+        .with_velocity([-30., 60.], [30., 140.])
+        .with_gravity([0., -60.])
+        .with_colors([1., 0.8, 0.3, 1.], [1., 0.2, 0., 0.])
+        .with_scale(1.5)
+        .with_layer(OBJECT_LAYER)
+        .with_lighting(false)
+        .with_lights(0.3, 0.5, 8);
+        if let Err(e) = self.renderer.add_emitter(sparks) {
+            eprintln!("{:?}", e);
+        }
+
         // TODO: This guy should also occlude
-        self.spawn_sprite(
+        let player = self.spawn_sprite(
             &TILES.player_walk_down_1,
             Translation {
                 position: Position {
@@ -330,19 +798,142 @@ impl World {
                     y: (20 * TILE_SIZE) as f32,
                 },
             },
+            OBJECT_LAYER,
             Types::PLAYER,
         );
-    }
+        self.animate(player, Self::player_animation());
 
-    fn move_lights(&mut self) {
-        let acc_t = self.time_tot;
-        self.lights.iter_mut().for_each(|l| {
-            let new_x = 0.4 * (l.frequency * acc_t.as_secs_f32()).sin();
-            let new_y = 0.4 * (l.frequency * acc_t.as_secs_f32()).cos();
-            l.position[0] += new_x;
-            l.position[1] += new_y;
-            l.intensity += 0.1 * (acc_t.as_secs_f32() * l.frequency).sin();
+        let mut fading = None;
+        for (index, MapLight { light, frequency }) in LIGHTS.into_iter().enumerate() {
+            let handle = self.add_light(light);
+            if index == FADING_LIGHT {
+                fading = Some((handle, frequency));
+            } else {
+                self.play(Self::light_flicker(handle, &light, frequency));
+            }
+        }
+        if let Some((handle, frequency)) = fading {
+            self.fade_in_light(handle, frequency);
+        }
+
+        // A neon tube along the top of the room with the pool, and a skylight in the open
+        // area at the bottom of the map
+        self.add_light(Light {
+            position: [(26 * TILE_SIZE) as f32, (13 * TILE_SIZE) as f32 + 24.],
+            intensity: 4.,
+            falloff: 0.2,
+            color: [1., 0.2, 0.8],
+            shape: LightShape::Line {
+                offset: [(8 * TILE_SIZE) as f32, 0.],
+                samples: 8,
+            },
         });
+        self.add_light(Light {
+            position: [(10 * TILE_SIZE) as f32, (3 * TILE_SIZE) as f32],
+            intensity: 4.,
+            falloff: 0.1,
+            color: [0.5, 0.7, 1.],
+            shape: LightShape::Rect {
+                size: [(3 * TILE_SIZE) as f32, (2 * TILE_SIZE) as f32],
+                samples: 3,
+            },
+        });
+
+        // A security camera at the top of the center corridor, sweeping from side to side
+        let camera = self.add_light(Light {
+            position: [(20 * TILE_SIZE) as f32, (21 * TILE_SIZE) as f32],
+            intensity: 6.,
+            falloff: 0.01,
+            color: [1., 0.95, 0.8],
+            shape: LightShape::Spot {
+                direction: -FRAC_PI_2,
+                inner_angle: 0.2,
+                outer_angle: 0.35,
+            },
+        });
+        let sweep = |direction: f32| {
+            Tween::new(
+                TweenTarget::LightDirection(camera),
+                direction,
+                SECURITY_CAMERA_SWEEP,
+            )
+            .with_easing(Easing::SineInOut)
+        };
+        self.play(
+            Sequence::new()
+                .then(sweep(-FRAC_PI_2 - 0.7))
+                .then(sweep(-FRAC_PI_2 + 0.7))
+                .looped(),
+        );
+    }
+
+    // Brighten and warm up a light, then leave it flickering
+    fn fade_in_light(&mut self, handle: LightHandle, frequency: f32) {
+        let fade = Duration::from_secs(2);
+        self.play(
+            Sequence::new()
+                .wait(Duration::from_secs(1))
+                .then(
+                    Tween::new(TweenTarget::LightIntensity(handle), 4., fade)
+                        .with_easing(Easing::QuadOut),
+                )
+                .with(Tween::new(
+                    TweenTarget::LightColor(handle),
+                    [1., 0.8, 0.5],
+                    fade,
+                ))
+                .then_call(move |world| {
+                    if let Some(light) = world.light(handle) {
+                        let flicker = Self::light_flicker(handle, light, frequency);
+                        world.play(flicker);
+                    }
+                }),
+        );
+    }
+
+    // Sway back and forth along the diagonal while pulsing, every 2π / frequency seconds
+    fn light_flicker(handle: LightHandle, light: &Light, frequency: f32) -> Sequence {
+        let half_period = Duration::from_secs_f32(PI / frequency);
+        let [x, y] = light.position;
+        let swing = |sign: f32| {
+            let to = [x + sign * LIGHT_SWAY, y + sign * LIGHT_SWAY];
+            Tween::new(TweenTarget::LightPosition(handle), to, half_period)
+                .with_easing(Easing::SineInOut)
+        };
+        let pulse = |sign: f32| {
+            let to = light.intensity * (1. + sign * LIGHT_PULSE);
+            Tween::new(TweenTarget::LightIntensity(handle), to, half_period)
+                .with_easing(Easing::SineInOut)
+        };
+        Sequence::new()
+            .then(swing(1.))
+            .with(pulse(1.))
+            .then(swing(-1.))
+            .with(pulse(-1.))
+            .looped()
+    }
+
+    fn player_animation() -> AnimationStateMachine {
+        let walk_frames = [
+            TILES.player_walk_down_1,
+            TILES.player_walk_down_2,
+            TILES.player_walk_down_3,
+            TILES.player_walk_down_4,
+        ];
+        // Only the walk down cycle has been drawn so far, so the player looks the same in
+        // every direction and needs just one idle and one walk state
+        let walk = Clip::new(&walk_frames, WALK_FRAME_DURATION, Playback::Loop);
+        let idle = Clip::new(&walk_frames[..1], WALK_FRAME_DURATION, Playback::Loop);
+
+        // Stopping and starting play the clips from their first frame
+        AnimationStateMachine::new(AnimationState::new("idle", idle))
+            .with_state(
+                AnimationState::new("walk", walk)
+                    .with_event(1, FOOTSTEP)
+                    .with_event(3, FOOTSTEP),
+            )
+            .with_transition(None, "walk", |p| p.is_moving())
+            .with_transition(None, "idle", |p| !p.is_moving())
     }
 
     fn move_player(&mut self) -> Option<(f32, f32)> {
@@ -353,22 +944,32 @@ impl World {
             .filter(|e| e.kind == Types::PLAYER)
             .enumerate()
             .for_each(|(id, e)| {
-                if let Some(instance) = self.sprite_instances.get_mut(e.id) {
-                    // let mut rng = rand::thread_rng();
-                    // let t = rng.gen_range(0..=3)
-                    if self.input.left {
-                        instance.translation.set_delta_x(-3.0 * delta_t);
-                    }
-                    if self.input.right {
-                        instance.translation.set_delta_x(3.0 * delta_t);
-                    }
-                    if self.input.up {
-                        instance.translation.set_delta_y(3.0 * delta_t);
-                    }
-                    if self.input.down {
-                        instance.translation.set_delta_y(-3.0 * delta_t);
+                let mut velocity = [0., 0.];
+                if self.input.left {
+                    velocity[0] -= 3.0;
+                }
+                if self.input.right {
+                    velocity[0] += 3.0;
+                }
+                if self.input.up {
+                    velocity[1] += 3.0;
+                }
+                if self.input.down {
+                    velocity[1] -= 3.0;
+                }
+                if let Some(animation) = e.animation.as_mut() {
+                    animation.params.set_velocity(velocity);
+                }
+                // get_mut marks the sprite dirty, so only take it when the player moves
+                if velocity != [0., 0.] {
+                    if let Some(instance) = self.sprite_instances.get_mut(e.sprite) {
+                        // let mut rng = rand::thread_rng();
+                        // let t = rng.gen_range(0..=3)
+                        instance.translation.set_delta_x(velocity[0] * delta_t);
+                        instance.translation.set_delta_y(velocity[1] * delta_t);
                     }
-
+                }
+                if let Some(instance) = self.sprite_instances.get(e.sprite) {
                     if id == 0 {
                         v = Some((instance.translation.x(), instance.translation.y()))
                     }